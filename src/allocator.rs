@@ -0,0 +1,165 @@
+//! The slot bookkeeping shared by all maps.
+
+use {Generation, HandleError, OverflowPolicy};
+
+/// Hands out the slots of a map and keeps their generations.
+///
+/// The maps store their elements by slot index themselves and
+/// tell the allocator which slots are occupied when checking
+/// a handle. Freed slots are reused, the most recently freed one
+/// first, unless the overflow policy retired them.
+pub(crate) struct SlotAllocator<G> {
+    generations: Vec<G>,
+    free: Vec<usize>,
+    overflow: OverflowPolicy,
+}
+
+impl<G> SlotAllocator<G>
+where
+    G: Generation,
+{
+    pub(crate) fn new() -> Self {
+        SlotAllocator::with_capacity(0)
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        SlotAllocator {
+            generations: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+
+    /// Reassembles an allocator from parts validated by the caller,
+    /// see `HandleMap::from_slots`.
+    pub(crate) fn from_parts(generations: Vec<G>, free: Vec<usize>, overflow: OverflowPolicy) -> Self {
+        SlotAllocator {
+            generations,
+            free,
+            overflow,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn generation(&self, index: usize) -> G {
+        self.generations[index]
+    }
+
+    /// Returns the index and generation of the slot `alloc` takes next.
+    pub(crate) fn peek(&self) -> (usize, G) {
+        match self.free.last() {
+            Some(&index) => (index, self.generations[index]),
+            None => (self.generations.len(), G::FIRST),
+        }
+    }
+
+    /// Takes a free slot, or adds a new one after all others if there
+    /// is none, in which case the map has to grow its storage.
+    ///
+    /// Maps take the slot of a new element before releasing the slot
+    /// of the element it replaces, so that the new element gets the
+    /// slot `peek` returned.
+    pub(crate) fn alloc(&mut self) -> usize {
+        match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.generations.push(G::FIRST);
+
+                self.generations.len() - 1
+            }
+        }
+    }
+
+    /// Advances the generation of the slot at `index`, so that all
+    /// handles to it become stale, according to the overflow policy.
+    ///
+    /// Returns `false` if the slot got retired.
+    pub(crate) fn bump(&mut self, index: usize) -> bool {
+        self.overflow.advance(&mut self.generations[index], index)
+    }
+
+    /// Makes all handles to the slot at `index` stale
+    /// and frees it, unless it got retired.
+    pub(crate) fn release(&mut self, index: usize) {
+        if self.bump(index) {
+            self.free.push(index);
+        }
+    }
+
+    /// Checks whether a handle with `generation` points to the
+    /// element in the slot at `index`. `occupied` is only called
+    /// for slots that exist and tells whether the slot holds an element.
+    pub(crate) fn check<F>(&self, index: usize, generation: G, occupied: F) -> Result<(), HandleError>
+    where
+        F: FnOnce(usize) -> bool,
+    {
+        let current = match self.generations.get(index) {
+            Some(&current) => current,
+            None => return Err(HandleError::OutOfBounds),
+        };
+
+        if generation > current && self.overflow != OverflowPolicy::Wrap {
+            // The slot never had such a generation.
+            Err(HandleError::WrongMap)
+        } else if generation != current || !occupied(index) {
+            Err(HandleError::Stale)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<G> SlotAllocator<G> {
+    /// Returns the generation of every slot.
+    #[inline]
+    pub(crate) fn generations(&self) -> &[G] {
+        &self.generations
+    }
+
+    /// Returns the free slots, the one `alloc` takes next last.
+    #[inline]
+    pub(crate) fn free(&self) -> &[usize] {
+        &self.free
+    }
+
+    #[inline]
+    pub(crate) fn overflow(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    #[inline]
+    pub(crate) fn set_overflow(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlotAllocator;
+    use {HandleError, OverflowPolicy};
+
+    #[test]
+    fn reuse_and_retire() {
+        let mut slots: SlotAllocator<u8> = SlotAllocator::new();
+
+        assert_eq!((0, 1), slots.peek());
+        assert_eq!(0, slots.alloc());
+        assert_eq!(1, slots.alloc());
+        assert_eq!(Ok(()), slots.check(0, 1, |_| true));
+        assert_eq!(Err(HandleError::OutOfBounds), slots.check(2, 1, |_| true));
+
+        slots.release(0);
+        assert_eq!(Err(HandleError::Stale), slots.check(0, 1, |_| false));
+        assert_eq!(Err(HandleError::WrongMap), slots.check(0, 3, |_| false));
+        assert_eq!((0, 2), slots.peek());
+        assert_eq!(0, slots.alloc());
+
+        while slots.bump(1) {}
+        slots.release(1);
+        assert_eq!(Err(HandleError::Stale), slots.check(1, u8::MAX, |_| false));
+        assert_eq!(2, slots.alloc());
+
+        slots.set_overflow(OverflowPolicy::Wrap);
+        assert_eq!(Err(HandleError::Stale), slots.check(0, 3, |_| true));
+    }
+}
@@ -0,0 +1,396 @@
+//! A `HandleMap` that can be shared between threads.
+//!
+//! Slots are spread over several shards, each behind its own
+//! `RwLock`, so readers only ever lock the one shard their handle
+//! points into and writers to different shards don't block each other.
+//! Handles are checked while the shard is locked, so a handle can
+//! never observe an element that replaced the one it pointed to.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::{LockResult, PoisonError};
+
+use fnv::FnvHashMap;
+
+use allocator::SlotAllocator;
+use handle::OwnedMapId;
+use {Generation, Handle, HandleError, OverflowPolicy};
+
+#[cfg(all(test, loom))]
+use loom::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(all(test, loom))]
+use loom::sync::RwLock;
+#[cfg(not(all(test, loom)))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(all(test, loom)))]
+use std::sync::RwLock;
+
+/// The number of shards of `ConcurrentHandleMap::new`.
+const SHARDS: usize = 16;
+
+/// A `HandleMap` that can be read and written from several threads
+/// at once through a shared reference.
+///
+/// Elements can't be borrowed past the lock of their shard, use
+/// `with`, `with_mut` or `get_cloned` to access them.
+pub struct ConcurrentHandleMap<V, K = String, G = u16> {
+    /// Always locked before a shard if both are locked.
+    keys_to_indices: RwLock<FnvHashMap<K, Handle<V, G>>>,
+    shards: Box<[LockedShard<V, K, G>]>,
+    next_shard: AtomicUsize,
+    len: AtomicUsize,
+    id: OwnedMapId,
+}
+
+type LockedShard<V, K, G> = RwLock<Shard<V, K, G>>;
+
+/// The slots `index * shards + shard` of a `ConcurrentHandleMap`.
+struct Shard<V, K, G> {
+    allocator: SlotAllocator<G>,
+    keys: Vec<Option<K>>,
+    storage: Vec<Option<V>>,
+}
+
+impl<V, K, G> ConcurrentHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    pub fn new() -> Self {
+        ConcurrentHandleMap::with_shards(SHARDS)
+    }
+
+    /// Creates a map with `shards` independently locked shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "A map needs at least one shard");
+
+        ConcurrentHandleMap {
+            keys_to_indices: RwLock::new(FnvHashMap::default()),
+            shards: (0..shards)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        allocator: SlotAllocator::new(),
+                        keys: Vec::new(),
+                        storage: Vec::new(),
+                    })
+                })
+                .collect(),
+            next_shard: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            id: OwnedMapId::next(),
+        }
+    }
+
+    /// Returns the handle stored under `key`.
+    pub fn handle<Q>(&self, key: &Q) -> Option<Handle<V, G>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        unpoison(self.keys_to_indices.read()).get(key).copied()
+    }
+
+    /// Returns the number of elements in the map.
+    ///
+    /// Other threads may change it at any time.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        unpoison(self.shards[0].read()).allocator.overflow()
+    }
+
+    /// See `HandleMap::set_overflow_policy`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        for shard in self.shards.iter() {
+            unpoison(shard.write()).allocator.set_overflow(policy);
+        }
+    }
+
+    /// Returns `true` if `handle` points to a live element of this map.
+    pub fn contains(&self, handle: Handle<V, G>) -> bool {
+        self.with(handle, |_| ()).is_some()
+    }
+
+    /// Calls `f` with the element `handle` points to, while its shard
+    /// is locked for reading. Returns `None` if the handle is dead.
+    pub fn with<F, R>(&self, handle: Handle<V, G>, f: F) -> Option<R>
+    where
+        F: FnOnce(&V) -> R,
+    {
+        let (shard, index) = self.locate(handle)?;
+        let shard = unpoison(shard.read());
+
+        shard.check(handle, index).ok()?;
+
+        shard.storage[index].as_ref().map(f)
+    }
+
+    /// Calls `f` with the element `handle` points to, while its shard
+    /// is locked for writing. Returns `None` if the handle is dead.
+    pub fn with_mut<F, R>(&self, handle: Handle<V, G>, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let (shard, index) = self.locate(handle)?;
+        let mut shard = unpoison(shard.write());
+
+        shard.check(handle, index).ok()?;
+
+        shard.storage[index].as_mut().map(f)
+    }
+
+    /// Returns a clone of the element `handle` points to.
+    pub fn get_cloned(&self, handle: Handle<V, G>) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.with(handle, V::clone)
+    }
+
+    /// Inserts a value under `key`, see `HandleMap::insert`.
+    pub fn insert<S>(&self, key: S, value: V) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+        let mut keys = unpoison(self.keys_to_indices.write());
+
+        let handle = self.push(Some(key.clone()), value);
+        if let Some(old) = keys.insert(key, handle) {
+            self.take(old);
+        }
+
+        handle
+    }
+
+    /// Inserts a value without a key.
+    pub fn insert_anonymous(&self, value: V) -> Handle<V, G> {
+        self.push(None, value)
+    }
+
+    /// Removes the element `handle` points to, freeing its slot
+    /// and invalidating all handles to it.
+    ///
+    /// Returns `None` if the element was already removed.
+    pub fn remove(&self, handle: Handle<V, G>) -> Option<V> {
+        let mut keys = unpoison(self.keys_to_indices.write());
+
+        let (key, value) = self.take(handle)?;
+        if let Some(key) = key {
+            keys.remove(&key);
+        }
+
+        Some(value)
+    }
+
+    /// Removes the element stored under `key`.
+    pub fn remove_key<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut keys = unpoison(self.keys_to_indices.write());
+
+        let handle = keys.remove(key)?;
+
+        self.take(handle).map(|(_, value)| value)
+    }
+
+    /// Stores `value` in a free slot of the next shard.
+    fn push(&self, key: Option<K>, value: V) -> Handle<V, G> {
+        let number = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let mut shard = unpoison(self.shards[number].write());
+
+        let index = shard.allocator.alloc();
+        if index == shard.storage.len() {
+            shard.keys.push(None);
+            shard.storage.push(None);
+        }
+        shard.keys[index] = key;
+        shard.storage[index] = Some(value);
+        self.len.fetch_add(1, Ordering::Release);
+
+        Handle::new(index * self.shards.len() + number, shard.allocator.generation(index), self.id.get())
+    }
+
+    /// Takes the element `handle` points to out of its slot, together
+    /// with its key. Does not touch `keys_to_indices`.
+    fn take(&self, handle: Handle<V, G>) -> Option<(Option<K>, V)> {
+        let (shard, index) = self.locate(handle)?;
+        let mut shard = unpoison(shard.write());
+
+        shard.check(handle, index).ok()?;
+
+        let value = shard.storage[index].take()?;
+        let key = shard.keys[index].take();
+        shard.allocator.release(index);
+        self.len.fetch_sub(1, Ordering::Release);
+
+        Some((key, value))
+    }
+
+    /// Returns the shard `handle` points into and the index within it,
+    /// or `None` if the handle is from a different map.
+    fn locate(&self, handle: Handle<V, G>) -> Option<(&LockedShard<V, K, G>, usize)> {
+        if handle.map != self.id.get() {
+            return None;
+        }
+
+        let shards = self.shards.len();
+
+        Some((&self.shards[handle.index % shards], handle.index / shards))
+    }
+}
+
+impl<V, K, G> Shard<V, K, G>
+where
+    G: Generation,
+{
+    /// Checks whether the slot at `index` holds the element of `handle`.
+    fn check(&self, handle: Handle<V, G>, index: usize) -> Result<(), HandleError> {
+        self.allocator.check(index, handle.generation, |index| self.storage[index].is_some())
+    }
+}
+
+impl<V, K, G> Default for ConcurrentHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn default() -> Self {
+        ConcurrentHandleMap::new()
+    }
+}
+
+/// Ignores lock poisoning. Every critical section of the map leaves it
+/// consistent before calling user code, which may panic.
+fn unpoison<T>(result: LockResult<T>) -> T {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use {ConcurrentHandleMap, OverflowPolicy};
+
+    #[test]
+    fn concurrent_inserts() {
+        let map: Arc<ConcurrentHandleMap<usize>> = Arc::new(ConcurrentHandleMap::with_shards(4));
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+
+                thread::spawn(move || (0..100).map(|i| map.insert(format!("{}-{}", t, i), t * 100 + i)).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut handles: Vec<_> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+
+        assert_eq!(400, map.len());
+        for &handle in &handles {
+            let value = map.get_cloned(handle).unwrap();
+            assert_eq!(Some(handle), map.handle(&format!("{}-{}", value / 100, value % 100)));
+        }
+
+        handles.sort();
+        handles.dedup();
+        assert_eq!(400, handles.len());
+    }
+
+    #[test]
+    fn remove_invalidates() {
+        let map: ConcurrentHandleMap<u32> = ConcurrentHandleMap::new();
+
+        let one = map.insert("one", 1);
+        let two = map.insert_anonymous(2);
+        map.with_mut(two, |v| *v += 1);
+
+        assert_eq!(Some(3), map.remove(two));
+        assert_eq!(None, map.with(two, |v| *v));
+        assert_eq!(Some(1), map.remove_key("one"));
+        assert!(!map.contains(one));
+        assert!(map.is_empty());
+
+        let three = map.insert_anonymous(3);
+        assert_ne!(one, three);
+        assert_ne!(two, three);
+    }
+
+    #[test]
+    fn wrap() {
+        let mut map: ConcurrentHandleMap<u32, String, u8> = ConcurrentHandleMap::with_shards(2);
+        map.set_overflow_policy(OverflowPolicy::Wrap);
+        assert_eq!(OverflowPolicy::Wrap, map.overflow_policy());
+
+        let first = map.insert_anonymous(0);
+        let mut last = first;
+        for i in 1..=u8::MAX as u32 {
+            map.remove(last);
+            // Stay in the same shard.
+            map.insert_anonymous(i);
+            last = map.insert_anonymous(i);
+        }
+
+        assert_eq!(first, last);
+        assert!(map.contains(first));
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use ConcurrentHandleMap;
+
+    #[test]
+    fn concurrent_inserts_are_unique() {
+        loom::model(|| {
+            let map: Arc<ConcurrentHandleMap<u32>> = Arc::new(ConcurrentHandleMap::with_shards(1));
+
+            let other = {
+                let map = map.clone();
+                thread::spawn(move || map.insert_anonymous(1))
+            };
+            let mine = map.insert("two", 2);
+            let other = other.join().unwrap();
+
+            assert_ne!(mine, other);
+            assert_eq!(Some(1), map.get_cloned(other));
+            assert_eq!(Some(2), map.get_cloned(mine));
+        });
+    }
+
+    #[test]
+    fn read_during_replace() {
+        loom::model(|| {
+            let map: Arc<ConcurrentHandleMap<u32>> = Arc::new(ConcurrentHandleMap::with_shards(1));
+            let old = map.insert_anonymous(1);
+
+            let reader = {
+                let map = map.clone();
+                thread::spawn(move || map.get_cloned(old))
+            };
+            map.remove(old);
+            let new = map.insert_anonymous(2);
+
+            // The reader sees the old element or nothing, never the new one.
+            assert_ne!(Some(2), reader.join().unwrap());
+            assert_eq!(old.index(), new.index());
+            assert!(!map.contains(old));
+        });
+    }
+}
@@ -0,0 +1,341 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+use fnv::FnvHashMap;
+
+use allocator::SlotAllocator;
+use handle::{MapId, OwnedMapId};
+use {Generation, Handle, HandleError, OverflowPolicy};
+
+/// A map like `HandleMap` that keeps its elements packed in a `Vec`.
+///
+/// Removing an element moves the last element into its place,
+/// so iteration is as fast as iterating a slice, but the order
+/// of the elements changes. Handles stay valid, they point to
+/// slots which know where their element currently is.
+pub struct DenseHandleMap<V, K = String, G = u16> {
+    allocator: SlotAllocator<G>,
+    /// The position of the element of every slot in `values`.
+    indices: Vec<Option<usize>>,
+    keys_to_indices: FnvHashMap<K, Handle<V, G>>,
+    values: Vec<V>,
+    /// The key of every element, indexed like `values`.
+    keys: Vec<Option<K>>,
+    /// The slot of every element, indexed like `values`.
+    slots: Vec<usize>,
+    id: OwnedMapId,
+}
+
+impl<V, K, G> DenseHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    #[inline]
+    pub fn new() -> Self {
+        DenseHandleMap::with_capacity(0)
+    }
+
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> Self {
+        DenseHandleMap {
+            allocator: SlotAllocator::with_capacity(capacity),
+            indices: Vec::with_capacity(capacity),
+            keys_to_indices: FnvHashMap::with_capacity_and_hasher(capacity, Default::default()),
+            values: Vec::with_capacity(capacity),
+            keys: Vec::with_capacity(capacity),
+            slots: Vec::with_capacity(capacity),
+            id: OwnedMapId::next(),
+        }
+    }
+
+    /// Returns the handle stored under `key`.
+    pub fn handle<Q>(&self, key: &Q) -> Option<Handle<V, G>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.keys_to_indices.get(key).copied()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.allocator.overflow()
+    }
+
+    /// See `HandleMap::set_overflow_policy`.
+    #[inline]
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.allocator.set_overflow(policy);
+    }
+
+    /// Returns `true` if `handle` points to a live element of this map.
+    #[inline]
+    pub fn contains(&self, handle: Handle<V, G>) -> bool {
+        self.check(handle).is_ok()
+    }
+
+    /// Returns a reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get(&self, handle: Handle<V, G>) -> Option<&V> {
+        let index = self.check(handle).ok()?;
+
+        Some(&self.values[index])
+    }
+
+    /// Returns a mutable reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get_mut(&mut self, handle: Handle<V, G>) -> Option<&mut V> {
+        let index = self.check(handle).ok()?;
+
+        Some(&mut self.values[index])
+    }
+
+    /// Returns the key of the element `handle` points to,
+    /// or `None` if it is dead or anonymous.
+    pub fn key_of(&self, handle: Handle<V, G>) -> Option<&K> {
+        let index = self.check(handle).ok()?;
+
+        self.keys[index].as_ref()
+    }
+
+    /// Returns all elements as a slice.
+    ///
+    /// The order changes whenever an element is removed.
+    #[inline]
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+
+    /// Returns all elements as a mutable slice.
+    #[inline]
+    pub fn values_mut(&mut self) -> &mut [V] {
+        &mut self.values
+    }
+
+    /// Returns an iterator over all handles, keys and elements,
+    /// in the order of `values`.
+    pub fn iter(&self) -> DenseIter<'_, V, K, G> {
+        DenseIter {
+            values: self.values.iter(),
+            keys: self.keys.iter(),
+            slots: self.slots.iter(),
+            generations: self.allocator.generations(),
+            map: self.id.get(),
+        }
+    }
+
+    /// Inserts a value under `key`, see `HandleMap::insert`.
+    pub fn insert<S>(&mut self, key: S, value: V) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+        let handle = self.push(value);
+
+        if let Some(old) = self.handle(&key) {
+            self.remove(old);
+        }
+        let index = self.indices[handle.index].expect("Bug: inserted element missing");
+        self.keys[index] = Some(key.clone());
+        self.keys_to_indices.insert(key, handle);
+
+        handle
+    }
+
+    /// Inserts a value without a key.
+    pub fn insert_anonymous(&mut self, value: V) -> Handle<V, G> {
+        self.push(value)
+    }
+
+    /// Removes the element `handle` points to, moving
+    /// the last element into its place.
+    ///
+    /// Returns `None` if the element was already removed.
+    pub fn remove(&mut self, handle: Handle<V, G>) -> Option<V> {
+        let index = self.check(handle).ok()?;
+
+        let value = self.values.swap_remove(index);
+        self.slots.swap_remove(index);
+        if let Some(key) = self.keys.swap_remove(index) {
+            self.keys_to_indices.remove(&key);
+        }
+        if let Some(&moved) = self.slots.get(index) {
+            self.indices[moved] = Some(index);
+        }
+
+        self.indices[handle.index] = None;
+        self.allocator.release(handle.index);
+
+        Some(value)
+    }
+
+    /// Removes the element stored under `key`.
+    pub fn remove_key<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let handle = self.handle(key)?;
+
+        self.remove(handle)
+    }
+
+    /// Appends `value` without a key and gives it a slot.
+    fn push(&mut self, value: V) -> Handle<V, G> {
+        let slot = self.allocator.alloc();
+        if slot == self.indices.len() {
+            self.indices.push(None);
+        }
+
+        self.indices[slot] = Some(self.values.len());
+        self.values.push(value);
+        self.keys.push(None);
+        self.slots.push(slot);
+
+        Handle::new(slot, self.allocator.generation(slot), self.id.get())
+    }
+
+    /// Checks whether `handle` points to a live element of this map,
+    /// returning the position of the element in `values`.
+    fn check(&self, handle: Handle<V, G>) -> Result<usize, HandleError> {
+        if handle.map != self.id.get() {
+            return Err(HandleError::WrongMap);
+        }
+
+        self.allocator.check(handle.index, handle.generation, |slot| self.indices[slot].is_some())?;
+
+        Ok(self.indices[handle.index].expect("Bug: checked slot is empty"))
+    }
+}
+
+impl<V, K, G> Default for DenseHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn default() -> Self {
+        DenseHandleMap::new()
+    }
+}
+
+impl<V, K, G> Index<Handle<V, G>> for DenseHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Output = V;
+
+    fn index(&self, handle: Handle<V, G>) -> &V {
+        match self.check(handle) {
+            Ok(index) => &self.values[index],
+            Err(e) => panic!("Tried to use invalid handle: {}", e),
+        }
+    }
+}
+
+impl<V, K, G> IndexMut<Handle<V, G>> for DenseHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn index_mut(&mut self, handle: Handle<V, G>) -> &mut V {
+        match self.check(handle) {
+            Ok(index) => &mut self.values[index],
+            Err(e) => panic!("Tried to use invalid handle: {}", e),
+        }
+    }
+}
+
+/// An iterator over the handles, keys and elements of a `DenseHandleMap`.
+pub struct DenseIter<'a, V: 'a, K: 'a = String, G: 'a = u16> {
+    values: slice::Iter<'a, V>,
+    keys: slice::Iter<'a, Option<K>>,
+    slots: slice::Iter<'a, usize>,
+    generations: &'a [G],
+    map: MapId,
+}
+
+impl<'a, V, K, G> Iterator for DenseIter<'a, V, K, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<V, G>, Option<&'a K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let value = self.values.next()?;
+        let key = self.keys.next().expect("Bug: element without key slot");
+        let slot = *self.slots.next().expect("Bug: element without slot");
+
+        Some((Handle::new(slot, self.generations[slot], self.map), key.as_ref(), value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for DenseIter<'a, V, K, G> where G: Generation {}
+
+#[cfg(test)]
+mod tests {
+    use DenseHandleMap;
+
+    #[test]
+    fn swap_remove_keeps_handles() {
+        let mut map: DenseHandleMap<_> = DenseHandleMap::new();
+
+        let one = map.insert("one", 1);
+        let two = map.insert("two", 2);
+        let three = map.insert_anonymous(3);
+
+        assert_eq!(Some(1), map.remove(one));
+        assert_eq!(None, map.remove(one));
+        assert_eq!(&[3, 2], map.values());
+        assert_eq!(2, map[two]);
+        assert_eq!(3, map[three]);
+        assert_eq!(None, map.handle("one"));
+
+        let four = map.insert("four", 4);
+        assert_eq!(one.index(), four.index());
+        assert!(!map.contains(one));
+
+        for value in map.values_mut() {
+            *value *= 10;
+        }
+        let entries: Vec<_> = map.iter().map(|(h, k, v)| (h, k.cloned(), *v)).collect();
+        assert_eq!(
+            vec![(three, None, 30), (two, Some("two".to_string()), 20), (four, Some("four".to_string()), 40)],
+            entries
+        );
+    }
+
+    #[test]
+    fn insert_existing_key() {
+        let mut map: DenseHandleMap<_> = DenseHandleMap::new();
+
+        let old = map.insert("one", 1);
+        let new = map.insert("one", 11);
+
+        assert_eq!(1, map.len());
+        assert!(!map.contains(old));
+        assert_eq!(Some(new), map.handle("one"));
+        assert_eq!(Some(&"one".to_string()), map.key_of(new));
+        assert_eq!(Some(11), map.remove_key("one"));
+        assert!(map.is_empty());
+    }
+}
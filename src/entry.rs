@@ -0,0 +1,219 @@
+use std::hash::Hash;
+
+use {Generation, Handle, HandleMap};
+
+/// A view into a single key of a `HandleMap`,
+/// which may either be vacant or occupied.
+///
+/// Returned by `HandleMap::entry`.
+pub enum Entry<'a, V: 'a, K: 'a + Hash + Eq = String, G: 'a + Generation = u16> {
+    /// There already is an element stored under the key.
+    Occupied(OccupiedEntry<'a, V, K, G>),
+    /// There is no element stored under the key yet.
+    Vacant(VacantEntry<'a, V, K, G>),
+}
+
+impl<'a, V, K, G> Entry<'a, V, K, G>
+where
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref e) => e.key(),
+            Entry::Vacant(ref e) => e.key(),
+        }
+    }
+
+    /// Inserts `default` if the entry is vacant and
+    /// returns the handle of the element.
+    pub fn or_insert(self, default: V) -> Handle<V, G> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Inserts the result of `default` if the entry is vacant
+    /// and returns the handle of the element.
+    ///
+    /// `default` is only called if the entry is vacant.
+    pub fn or_insert_with<F>(self, default: F) -> Handle<V, G>
+    where
+        F: FnOnce() -> V,
+    {
+        self.or_insert_with_handle(|_| default())
+    }
+
+    /// Like `or_insert_with`, but passes the handle the element
+    /// is going to get to `default`.
+    pub fn or_insert_with_handle<F>(self, default: F) -> Handle<V, G>
+    where
+        F: FnOnce(Handle<V, G>) -> V,
+    {
+        match self {
+            Entry::Occupied(e) => e.handle(),
+            Entry::Vacant(e) => e.insert_with_handle(default),
+        }
+    }
+
+    /// Calls `f` with the element if the entry is occupied.
+    pub fn and_modify<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        if let Entry::Occupied(ref mut e) = self {
+            f(e.get_mut());
+        }
+
+        self
+    }
+}
+
+/// An occupied entry of a `HandleMap`.
+pub struct OccupiedEntry<'a, V: 'a, K: 'a + Hash + Eq = String, G: 'a + Generation = u16> {
+    pub(crate) key: K,
+    pub(crate) handle: Handle<V, G>,
+    pub(crate) map: &'a mut HandleMap<V, K, G>,
+}
+
+impl<'a, V, K, G> OccupiedEntry<'a, V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the handle of the element.
+    pub fn handle(&self) -> Handle<V, G> {
+        self.handle
+    }
+
+    pub fn get(&self) -> &V {
+        &self.map[self.handle]
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.map[self.handle]
+    }
+
+    /// Converts the entry into a mutable reference
+    /// bound to the lifetime of the map.
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.map[self.handle]
+    }
+
+    /// Replaces the element, invalidating the previous handles.
+    ///
+    /// See `HandleMap::replace`.
+    pub fn replace(&mut self, value: V) -> V {
+        let value = self.map.replace(self.handle, value);
+        self.handle = self.map.keys_to_indices[&self.key];
+
+        value
+    }
+
+    /// Removes the element from the map.
+    pub fn remove(self) -> V {
+        self.map.remove_key(&self.key).expect("Bug: occupied entry without element")
+    }
+}
+
+/// A vacant entry of a `HandleMap`.
+pub struct VacantEntry<'a, V: 'a, K: 'a + Hash + Eq = String, G: 'a + Generation = u16> {
+    pub(crate) key: K,
+    pub(crate) map: &'a mut HandleMap<V, K, G>,
+}
+
+impl<'a, V, K, G> VacantEntry<'a, V, K, G>
+where
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Returns the handle the element will get once it is inserted.
+    pub fn handle(&self) -> Handle<V, G> {
+        self.map.next_handle()
+    }
+
+    /// Inserts `value` under the key of this entry.
+    pub fn insert(self, value: V) -> Handle<V, G> {
+        self.map.insert(self.key, value)
+    }
+
+    /// Like `insert`, but passes the handle the element
+    /// is going to get to `f`.
+    pub fn insert_with_handle<F>(self, f: F) -> Handle<V, G>
+    where
+        F: FnOnce(Handle<V, G>) -> V,
+    {
+        let value = f(self.handle());
+
+        self.insert(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Entry, Handle, HandleMap};
+
+    #[test]
+    fn load_once() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let mut loads = 0;
+
+        let first = map.entry("tex").or_insert_with(|| {
+            loads += 1;
+            1
+        });
+        let second = map.entry("tex").or_insert_with(|| {
+            loads += 1;
+            2
+        });
+
+        assert_eq!(1, loads);
+        assert_eq!(first, second);
+        assert_eq!(1, map[first]);
+
+        map.entry("tex").and_modify(|v| *v += 10).or_insert(0);
+        assert_eq!(11, map[first]);
+    }
+
+    #[test]
+    fn insert_with_handle() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        map.entry("other").or_insert_with_handle(Handle::erase);
+        let handle = map.entry("self").or_insert_with_handle(Handle::erase);
+
+        assert_eq!(handle.erase(), map[handle]);
+    }
+
+    #[test]
+    fn occupied_entry() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let old_handle = map.insert("one", 1);
+
+        match map.entry("one") {
+            Entry::Occupied(mut e) => {
+                assert_eq!(1, e.replace(2));
+                assert_ne!(old_handle, e.handle());
+                assert_eq!(2, e.remove());
+            }
+            Entry::Vacant(_) => panic!("expected occupied entry"),
+        }
+
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// An unsigned integer type used to count how often a slot was reused.
+///
+/// Implemented for `u8`, `u16`, `u32` and `u64`. Smaller types make
+/// handles smaller, but overflow sooner; see `OverflowPolicy` for
+/// what happens then.
+pub trait Generation: Copy + Debug + Eq + Hash + Ord + private::Sealed {
+    /// The generation of a slot that was never reused.
+    ///
+    /// This is `1`, generations are never zero.
+    const FIRST: Self;
+
+    /// Returns the next generation, or `None` on overflow.
+    fn checked_next(self) -> Option<Self>;
+}
+
+macro_rules! impl_generation {
+    ($($ty:ty),*) => {
+        $(
+            impl Generation for $ty {
+                const FIRST: Self = 1;
+
+                #[inline]
+                fn checked_next(self) -> Option<Self> {
+                    self.checked_add(1)
+                }
+            }
+
+            impl private::Sealed for $ty {}
+        )*
+    };
+}
+
+impl_generation!(u8, u16, u32, u64);
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// What a `HandleMap` does once the generation of a slot
+/// cannot be increased any further.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OverflowPolicy {
+    /// Never use the slot again. Costs the memory of one slot,
+    /// but old handles can never become valid again.
+    #[default]
+    Retire,
+    /// Start over at the first generation. Handles that are
+    /// exactly one full cycle old will be valid again.
+    Wrap,
+    /// Panic.
+    Panic,
+}
+
+impl OverflowPolicy {
+    /// Advances the generation of the slot at `index`
+    /// according to this policy.
+    ///
+    /// Returns `false` if the slot got retired.
+    pub(crate) fn advance<G: Generation>(self, generation: &mut G, index: usize) -> bool {
+        match (generation.checked_next(), self) {
+            (Some(next), _) => *generation = next,
+            (None, OverflowPolicy::Retire) => return false,
+            (None, OverflowPolicy::Wrap) => *generation = G::FIRST,
+            (None, OverflowPolicy::Panic) => panic!("Generation overflow in slot {}", index),
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {HandleMap, OverflowPolicy};
+
+    fn cycle(map: &mut HandleMap<u32, String, u8>) {
+        for i in 1..u8::MAX as u32 {
+            let handle = map.insert("cycled", i);
+            map.remove(handle);
+        }
+    }
+
+    #[test]
+    fn retire() {
+        let mut map: HandleMap<u32, String, u8> = HandleMap::new();
+
+        let first = map.insert("first", 0);
+        map.remove(first);
+        cycle(&mut map);
+
+        let handle = map.insert("fresh", 1);
+
+        assert_ne!(first.index(), handle.index());
+        assert!(!map.contains(first));
+        assert_eq!(1, map.len());
+    }
+
+    #[test]
+    fn retire_on_replace() {
+        let mut map: HandleMap<u32, String, u8> = HandleMap::new();
+
+        let handle = map.insert("one", 0);
+        for i in 1..u8::MAX as u32 {
+            map.replace(map.handle("one").unwrap(), i);
+        }
+
+        let last = map.handle("one").unwrap();
+        assert_eq!(handle.index(), last.index());
+
+        map.replace(last, 256);
+        let moved = map.handle("one").unwrap();
+
+        assert_ne!(handle.index(), moved.index());
+        assert_eq!(256, map[moved]);
+        assert_eq!(1, map.len());
+    }
+
+    #[test]
+    fn wrap() {
+        let mut map: HandleMap<u32, String, u8> = HandleMap::new();
+        map.set_overflow_policy(OverflowPolicy::Wrap);
+
+        let first = map.insert("first", 0);
+        map.remove(first);
+        cycle(&mut map);
+
+        let handle = map.insert("fresh", 1);
+
+        assert_eq!(first, handle);
+        assert!(map.contains(first));
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic() {
+        let mut map: HandleMap<u32, String, u8> = HandleMap::new();
+        map.set_overflow_policy(OverflowPolicy::Panic);
+
+        let first = map.insert("first", 0);
+        map.remove(first);
+        cycle(&mut map);
+    }
+}
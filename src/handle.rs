@@ -0,0 +1,325 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+#[cfg(feature = "map-id")]
+use std::collections::BTreeSet;
+#[cfg(feature = "map-id")]
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+#[cfg(feature = "map-id")]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use generation::Generation;
+
+/// A handle to an element of a `HandleMap<T>`,
+/// where `G` is the generation type of the map.
+///
+/// The type parameter ties the handle to maps storing `T`,
+/// so using it with a map of another element type does not compile.
+/// `Handle<()>` (or just `Handle`) is the untyped handle,
+/// see `erase` and `cast`.
+///
+/// ```compile_fail
+/// use handle_map::HandleMap;
+///
+/// let mut textures: HandleMap<String> = HandleMap::new();
+/// let meshes: HandleMap<Vec<f32>> = HandleMap::new();
+///
+/// let handle = textures.insert("hero", "hero.png".to_string());
+/// meshes.get(handle);
+/// ```
+pub struct Handle<T = (), G = u16> {
+    pub(crate) index: usize,
+    pub(crate) generation: G,
+    pub(crate) map: MapId,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T, G> Handle<T, G>
+where
+    G: Generation,
+{
+    pub(crate) fn new(index: usize, generation: G, map: MapId) -> Self {
+        Handle {
+            index,
+            generation,
+            map,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Reinterprets this handle as a handle to a `U`.
+    ///
+    /// Nothing checks that the handle actually
+    /// came from a map storing `U`s.
+    pub fn cast<U>(self) -> Handle<U, G> {
+        Handle::new(self.index, self.generation, self.map)
+    }
+
+    /// Drops the element type, e.g. to store
+    /// handles to different maps together.
+    pub fn erase(self) -> Handle<(), G> {
+        self.cast()
+    }
+}
+
+impl<T, G: Generation> Clone for Handle<T, G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, G: Generation> Copy for Handle<T, G> {}
+
+impl<T, G: Generation> fmt::Debug for Handle<T, G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Handle")
+            .field("index", &self.index)
+            .field("generation", &self.generation)
+            .field("map", &self.map)
+            .finish()
+    }
+}
+
+impl<T, G: Generation> PartialEq for Handle<T, G> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation && self.map == other.map
+    }
+}
+
+impl<T, G: Generation> Eq for Handle<T, G> {}
+
+impl<T, G: Generation> PartialOrd for Handle<T, G> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, G: Generation> Ord for Handle<T, G> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.index, self.generation, self.map).cmp(&(other.index, other.generation, other.map))
+    }
+}
+
+impl<T, G: Generation> Hash for Handle<T, G> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+        self.map.hash(state);
+    }
+}
+
+/// Identifies the map a handle was created by.
+///
+/// Without the `map-id` feature this is zero-sized
+/// and all maps compare equal.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) struct MapId(#[cfg(feature = "map-id")] u32);
+
+impl MapId {
+    #[cfg(not(feature = "map-id"))]
+    pub(crate) fn next() -> Self {
+        MapId()
+    }
+
+    /// Returns the raw id, for serialization.
+    /// Always zero without the `map-id` feature.
+    #[cfg(feature = "map-id")]
+    pub(crate) fn to_raw(self) -> u32 {
+        self.0
+    }
+
+    #[cfg(not(feature = "map-id"))]
+    pub(crate) fn to_raw(self) -> u32 {
+        0
+    }
+
+    /// Recreates the id of a deserialized handle.
+    #[cfg(all(feature = "map-id", feature = "serde"))]
+    pub(crate) fn from_raw(id: u32) -> Self {
+        MapId(id)
+    }
+
+    #[cfg(not(feature = "map-id"))]
+    pub(crate) fn from_raw(_: u32) -> Self {
+        MapId()
+    }
+}
+
+/// The id of a map, registered as in use until the map is dropped,
+/// so that no two live maps ever share an id.
+#[derive(Debug)]
+pub(crate) struct OwnedMapId(MapId);
+
+impl OwnedMapId {
+    #[cfg(feature = "map-id")]
+    pub(crate) fn next() -> Self {
+        OwnedMapId(registry().next())
+    }
+
+    #[cfg(not(feature = "map-id"))]
+    pub(crate) fn next() -> Self {
+        OwnedMapId(MapId::next())
+    }
+
+    /// Takes the saved id of a loaded map, unless a live map
+    /// already has it, e.g. because the same snapshot was loaded
+    /// twice. In that case the map gets a new id, so the handles
+    /// saved with it belong to the live map and not to this one.
+    ///
+    /// Maps saved without the `map-id` feature have the id zero,
+    /// which no map gets otherwise. Any number of maps may share it,
+    /// they accept the handles saved with them, like maps without the
+    /// feature do.
+    #[cfg(feature = "map-id")]
+    pub(crate) fn claim(id: u32) -> Self {
+        if id == UNTAGGED {
+            return OwnedMapId(MapId(UNTAGGED));
+        }
+
+        let mut registry = registry();
+        if registry.live.insert(id) {
+            // Prefer ids after it for new maps, so that saved handles
+            // are less likely to match a map they were not made for.
+            NEXT.fetch_max(id.saturating_add(1), AtomicOrdering::Relaxed);
+
+            return OwnedMapId(MapId(id));
+        }
+
+        OwnedMapId(registry.next())
+    }
+
+    #[cfg(not(feature = "map-id"))]
+    pub(crate) fn claim(id: u32) -> Self {
+        OwnedMapId(MapId::from_raw(id))
+    }
+
+    pub(crate) fn get(&self) -> MapId {
+        self.0
+    }
+}
+
+#[cfg(feature = "map-id")]
+impl Drop for OwnedMapId {
+    fn drop(&mut self) {
+        if self.0 .0 != UNTAGGED {
+            registry().live.remove(&self.0 .0);
+        }
+    }
+}
+
+/// The id of maps saved without the `map-id` feature.
+#[cfg(feature = "map-id")]
+const UNTAGGED: u32 = 0;
+
+#[cfg(feature = "map-id")]
+static NEXT: AtomicU32 = AtomicU32::new(UNTAGGED + 1);
+
+/// The ids of all live maps.
+#[cfg(feature = "map-id")]
+struct Registry {
+    live: BTreeSet<u32>,
+}
+
+#[cfg(feature = "map-id")]
+impl Registry {
+    /// Registers the next id that is not in use. Ids wrap around,
+    /// so this skips the ids of maps that are still alive.
+    fn next(&mut self) -> MapId {
+        loop {
+            let id = NEXT.fetch_add(1, AtomicOrdering::Relaxed);
+            if id != UNTAGGED && self.live.insert(id) {
+                return MapId(id);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "map-id")]
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { live: BTreeSet::new() });
+
+#[cfg(feature = "map-id")]
+fn registry() -> MutexGuard<'static, Registry> {
+    // The registry is consistent after every statement.
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use {Handle, HandleMap};
+
+    #[test]
+    fn cast_and_erase() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+
+        let handle = map.insert("one", 1);
+        let erased: Handle = handle.erase();
+
+        assert_eq!(handle.index(), erased.index());
+        assert_eq!(1, map[erased.cast::<u32>()]);
+        assert_eq!(handle, erased.cast());
+    }
+
+    #[cfg(feature = "map-id")]
+    #[test]
+    fn foreign_handle() {
+        use HandleError;
+
+        let mut map: HandleMap<u32> = HandleMap::new();
+        let mut other: HandleMap<u32> = HandleMap::new();
+
+        map.insert("one", 1);
+        let handle = other.insert("one", 1);
+
+        assert!(!map.contains(handle));
+        assert_eq!(None, map.get(handle));
+        assert_eq!(Err(HandleError::WrongMap), map.try_replace(handle, 2));
+    }
+
+    #[cfg(feature = "map-id")]
+    #[test]
+    fn loaded_ids_stay_unique() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+        let one = map.insert("one", 1);
+        let mut bytes = Vec::new();
+        map.write_snapshot(&mut bytes).unwrap();
+
+        // The saved id is still in use by `map`, which keeps its handles.
+        let copy: HandleMap<u32> = HandleMap::read_snapshot(&bytes[..]).unwrap();
+        let other: HandleMap<u32> = HandleMap::read_snapshot(&bytes[..]).unwrap();
+        let in_copy = copy.handle("one").unwrap();
+
+        assert!(map.contains(one));
+        assert!(!copy.contains(one));
+        assert!(!other.contains(one));
+        assert!(!other.contains(in_copy));
+        assert!(!map.contains(in_copy));
+        assert_eq!(Some(&1), map.get(one));
+
+        // Once no map has the saved id, a loaded map takes it again.
+        drop(map);
+        drop(copy);
+        drop(other);
+        let map: HandleMap<u32> = HandleMap::read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(Some(&1), map.get(one));
+    }
+
+    #[cfg(feature = "map-id")]
+    #[test]
+    fn ids_wrap_around_live_ones() {
+        use super::OwnedMapId;
+
+        let last = OwnedMapId::claim(u32::MAX);
+        let first = OwnedMapId::next();
+        let second = OwnedMapId::next();
+
+        assert_eq!(u32::MAX, last.get().to_raw());
+        assert_ne!(last.get(), first.get());
+        assert_ne!(last.get(), second.get());
+        assert_ne!(first.get(), second.get());
+    }
+}
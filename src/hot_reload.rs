@@ -0,0 +1,352 @@
+//! Reloading elements of a `LoadingHandleMap` when their files change.
+//!
+//! Keys are paths relative to a watched directory. The directory is
+//! not watched by the OS, `poll_changes` compares the modification
+//! times of the files instead, so it should be called periodically,
+//! e.g. once per frame. Checking a file costs one `stat` call, so
+//! `poll_changes` is rate-limited and only checks a batch of files
+//! per call, see `LoadingHandleMap::watch_with`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use fnv::FnvHashMap;
+
+use {Generation, Handle, Loader, LoadingHandleMap};
+
+/// The minimum time between two polls of `LoadingHandleMap::watch`.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The number of files checked per poll of `LoadingHandleMap::watch`.
+const POLL_BATCH: usize = 256;
+
+/// Something that happened during `LoadingHandleMap::poll_changes`.
+pub enum ReloadEvent<V, E, G = u16> {
+    /// The element was reloaded, its handles stay valid.
+    Reloaded { handle: Handle<V, G>, version: u64 },
+    /// Reloading failed, the element was kept.
+    Failed { handle: Handle<V, G>, error: E },
+}
+
+impl<V, E, G> fmt::Debug for ReloadEvent<V, E, G>
+where
+    E: fmt::Debug,
+    G: Generation,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReloadEvent::Reloaded { handle, version } => f
+                .debug_struct("Reloaded")
+                .field("handle", &handle)
+                .field("version", &version)
+                .finish(),
+            ReloadEvent::Failed { handle, ref error } => f
+                .debug_struct("Failed")
+                .field("handle", &handle)
+                .field("error", error)
+                .finish(),
+        }
+    }
+}
+
+/// The state of a watched directory.
+pub(crate) struct Watch<V, K, E, G> {
+    root: PathBuf,
+    /// Returns the modification time of the file of a key,
+    /// so that elements can be watched without knowing that
+    /// `K: AsRef<Path>`.
+    modified: fn(&Path, &K) -> Option<SystemTime>,
+    /// The watched elements, by slot index.
+    entries: FnvHashMap<usize, Watched<V, G>>,
+    events: VecDeque<ReloadEvent<V, E, G>>,
+    interval: Duration,
+    batch: usize,
+    last_poll: Option<Instant>,
+    /// The slot index the next poll continues at, wrapping around.
+    cursor: usize,
+}
+
+struct Watched<V, G> {
+    handle: Handle<V, G>,
+    modified: Option<SystemTime>,
+    version: u64,
+}
+
+impl<V, K, E, G> Watch<V, K, E, G>
+where
+    G: Generation,
+{
+    /// Remembers the modification time of a freshly loaded element.
+    pub(crate) fn loaded(&mut self, handle: Handle<V, G>, key: &K) {
+        let modified = (self.modified)(&self.root, key);
+
+        self.entries.insert(handle.index(), Watched { handle, modified, version: 0 });
+    }
+}
+
+impl<V, L, K, G> LoadingHandleMap<V, L, K, G>
+where
+    L: Loader<V, K>,
+    K: Hash + Eq + Clone + AsRef<Path>,
+    G: Generation,
+{
+    /// Starts watching the files under `root`, which are the
+    /// keys of the elements, see `poll_changes`.
+    ///
+    /// Polls at most every 100 ms and checks 256 files per poll,
+    /// see `watch_with`.
+    pub fn watch<P>(&mut self, root: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.watch_with(root, POLL_INTERVAL, POLL_BATCH);
+    }
+
+    /// Starts watching the files under `root`. `poll_changes` does
+    /// nothing if it was called less than `interval` ago, and checks
+    /// at most `batch` files, continuing with the next files on the
+    /// next poll. A change is thus noticed within `n / batch` polls
+    /// for `n` elements with keys.
+    ///
+    /// Replaces the previously watched directory, if any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is zero.
+    pub fn watch_with<P>(&mut self, root: P, interval: Duration, batch: usize)
+    where
+        P: Into<PathBuf>,
+    {
+        assert!(batch > 0, "Polls must check at least one file");
+
+        let mut watch = Watch {
+            root: root.into(),
+            modified: modified::<K>,
+            entries: FnvHashMap::default(),
+            events: VecDeque::new(),
+            interval,
+            batch,
+            last_poll: None,
+            cursor: 0,
+        };
+        for (handle, key, _) in self.map.iter() {
+            if let Some(key) = key {
+                watch.loaded(handle, key);
+            }
+        }
+
+        self.watch = Some(watch);
+    }
+
+    /// Stops watching, dropping all pending events.
+    pub fn unwatch(&mut self) {
+        self.watch = None;
+    }
+
+    /// Reloads the elements whose file was modified since they were
+    /// loaded, keeping their handles valid and increasing their version.
+    ///
+    /// Checks the next batch of files, unless the previous poll was
+    /// too recent, see `watch_with`. Every checked file costs one
+    /// `stat` call. Only the slots up to the last checked file are
+    /// visited, so a full pass over all slots takes O(n) time in total.
+    /// Every reload is reported as a `ReloadEvent`, see `poll_event`.
+    /// Returns the number of reloaded elements.
+    pub fn poll_changes(&mut self) -> usize {
+        let map = &mut self.map;
+        let loader = &mut self.loader;
+        let watch = match self.watch {
+            Some(ref mut watch) => watch,
+            None => return 0,
+        };
+
+        let now = Instant::now();
+        if watch.last_poll.is_some_and(|last| now.duration_since(last) < watch.interval) {
+            return 0;
+        }
+        watch.last_poll = Some(now);
+
+        let mut reloaded = Vec::new();
+        let slots = map.storage.len();
+        let start = watch.cursor;
+        let mut checked = 0;
+        for visited in 0..slots {
+            if checked == watch.batch {
+                break;
+            }
+
+            let index = (start + visited) % slots;
+            let key = match map.keys[index] {
+                Some(ref key) if map.storage[index].is_some() => key,
+                _ => {
+                    // Forget removed elements on the way.
+                    watch.entries.remove(&index);
+                    continue;
+                }
+            };
+            let handle = map.handle_at(index);
+            checked += 1;
+            watch.cursor = index + 1;
+
+            let modified = (watch.modified)(&watch.root, key);
+            let entry = watch.entries.entry(index).or_insert(Watched { handle, modified, version: 0 });
+            if entry.handle != handle {
+                // The slot was reused, start watching the new element.
+                *entry = Watched { handle, modified, version: 0 };
+            }
+            if modified.is_none() || modified == entry.modified {
+                // Unchanged, or in the middle of being saved.
+                continue;
+            }
+
+            entry.modified = modified;
+            match loader.load(key) {
+                Ok(value) => reloaded.push((handle, value)),
+                Err(error) => watch.events.push_back(ReloadEvent::Failed { handle, error }),
+            }
+        }
+
+        let count = reloaded.len();
+        for (handle, value) in reloaded {
+            map[handle] = value;
+
+            let entry = watch.entries.get_mut(&handle.index()).expect("Bug: reloaded element not watched");
+            entry.version += 1;
+            watch.events.push_back(ReloadEvent::Reloaded {
+                handle,
+                version: entry.version,
+            });
+        }
+
+        count
+    }
+
+    /// Returns the oldest event of `poll_changes` that wasn't returned yet.
+    pub fn poll_event(&mut self) -> Option<ReloadEvent<V, L::Error, G>> {
+        self.watch.as_mut()?.events.pop_front()
+    }
+
+    /// Returns how often the element `handle` points to was reloaded,
+    /// or `None` if it is dead or not watched.
+    pub fn version(&self, handle: Handle<V, G>) -> Option<u64> {
+        let watched = self.watch.as_ref()?.entries.get(&handle.index())?;
+
+        if watched.handle == handle && self.map.contains(handle) {
+            Some(watched.version)
+        } else {
+            None
+        }
+    }
+}
+
+fn modified<K>(root: &Path, key: &K) -> Option<SystemTime>
+where
+    K: AsRef<Path>,
+{
+    fs::metadata(root.join(key)).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::process;
+    use std::time::{Duration, SystemTime};
+
+    use {LoadingHandleMap, ReloadEvent};
+
+    fn touch(path: &Path, contents: &str, age: u64) {
+        fs::write(path, contents).unwrap();
+
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_handles() {
+        let root = env::temp_dir().join(format!("handle-map-hot-reload-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        touch(&root.join("hero.txt"), "hero", 60);
+
+        let dir = root.clone();
+        let mut map: LoadingHandleMap<String, _> =
+            LoadingHandleMap::new(move |key: &String| fs::read_to_string(dir.join(key)));
+        map.watch_with(&root, Duration::from_secs(0), 4);
+
+        let hero = map.get_or_load("hero.txt").unwrap();
+        let anonymous = map.insert_anonymous("anonymous".to_string());
+        assert_eq!(Some(0), map.version(hero));
+        assert_eq!(None, map.version(anonymous));
+        assert_eq!(0, map.poll_changes());
+
+        touch(&root.join("hero.txt"), "new hero", 30);
+        assert_eq!(1, map.poll_changes());
+        assert_eq!("new hero", map[hero]);
+        assert_eq!(Some(1), map.version(hero));
+        match map.poll_event() {
+            Some(ReloadEvent::Reloaded { handle, version: 1 }) if handle == hero => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        fs::remove_file(root.join("hero.txt")).unwrap();
+        assert_eq!(0, map.poll_changes());
+        fs::create_dir(root.join("hero.txt")).unwrap();
+        assert_eq!(0, map.poll_changes());
+        match map.poll_event() {
+            Some(ReloadEvent::Failed { handle, ref error }) if handle == hero => {
+                assert_ne!(io::ErrorKind::NotFound, error.kind())
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(map.poll_event().is_none());
+        assert_eq!("new hero", map[hero]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn poll_in_batches() {
+        let root = env::temp_dir().join(format!("handle-map-hot-reload-batches-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        for name in &["a.txt", "b.txt", "c.txt"] {
+            touch(&root.join(name), name, 60);
+        }
+
+        let dir = root.clone();
+        let mut map: LoadingHandleMap<String, _> =
+            LoadingHandleMap::new(move |key: &String| fs::read_to_string(dir.join(key)));
+        map.watch_with(&root, Duration::from_secs(0), 2);
+        let handles: Vec<_> = ["a.txt", "b.txt", "c.txt"].iter().map(|name| map.get_or_load(*name).unwrap()).collect();
+
+        for name in &["a.txt", "b.txt", "c.txt"] {
+            touch(&root.join(name), "changed", 30);
+        }
+        assert_eq!(2, map.poll_changes());
+        assert_eq!("c.txt", map[handles[2]]);
+        assert_eq!(1, map.poll_changes());
+        assert_eq!(0, map.poll_changes());
+        assert!(handles.iter().all(|&handle| map[handle] == "changed"));
+
+        map.watch_with(&root, Duration::from_secs(3600), 2);
+        touch(&root.join("a.txt"), "again", 0);
+        assert_eq!(1, map.poll_changes());
+        touch(&root.join("b.txt"), "again", 0);
+        assert_eq!(0, map.poll_changes());
+        assert_eq!("changed", map[handles[1]]);
+
+        // A reused slot is watched for the new element only.
+        touch(&root.join("d.txt"), "d", 60);
+        map.remove(handles[0]);
+        let d = map.get_or_load("d.txt").unwrap();
+        assert_eq!(handles[0].index(), d.index());
+        assert_eq!(None, map.version(handles[0]));
+        assert_eq!(Some(0), map.version(d));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
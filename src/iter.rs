@@ -0,0 +1,373 @@
+use std::collections::hash_map;
+use std::hash::Hash;
+use std::iter::{Enumerate, FromIterator, Zip};
+use std::marker::PhantomData;
+use std::{slice, vec};
+
+use handle::MapId;
+use {Generation, Handle, HandleMap};
+
+/// An iterator over the handles, keys and elements of a `HandleMap`.
+pub struct Iter<'a, V: 'a, K: 'a = String, G: 'a = u16> {
+    pub(crate) slots: Enumerate<slice::Iter<'a, Option<V>>>,
+    pub(crate) generations: &'a [G],
+    pub(crate) keys: &'a [Option<K>],
+    pub(crate) map: MapId,
+    pub(crate) remaining: usize,
+}
+
+impl<'a, V, K, G> Iterator for Iter<'a, V, K, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<V, G>, Option<&'a K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (index, slot) in &mut self.slots {
+            if let Some(value) = slot.as_ref() {
+                let handle = Handle::new(index, self.generations[index], self.map);
+                self.remaining -= 1;
+
+                return Some((handle, self.keys[index].as_ref(), value));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for Iter<'a, V, K, G> where G: Generation {}
+
+/// A mutable iterator over the handles, keys and elements of a `HandleMap`.
+pub struct IterMut<'a, V: 'a, K: 'a = String, G: 'a = u16> {
+    pub(crate) slots: Enumerate<slice::IterMut<'a, Option<V>>>,
+    pub(crate) generations: &'a [G],
+    pub(crate) keys: &'a [Option<K>],
+    pub(crate) map: MapId,
+    pub(crate) remaining: usize,
+}
+
+impl<'a, V, K, G> Iterator for IterMut<'a, V, K, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<V, G>, Option<&'a K>, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (index, slot) in &mut self.slots {
+            if let Some(value) = slot.as_mut() {
+                let handle = Handle::new(index, self.generations[index], self.map);
+                self.remaining -= 1;
+
+                return Some((handle, self.keys[index].as_ref(), value));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for IterMut<'a, V, K, G> where G: Generation {}
+
+/// An iterator over the keys of a `HandleMap`.
+pub struct Keys<'a, V: 'a, K: 'a = String, G: 'a = u16> {
+    pub(crate) keys: hash_map::Keys<'a, K, Handle<V, G>>,
+}
+
+impl<'a, V, K, G> Iterator for Keys<'a, V, K, G> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.keys.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.keys.size_hint()
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for Keys<'a, V, K, G> {}
+
+/// An iterator over the handles of a `HandleMap`.
+pub struct Handles<'a, V: 'a, K: 'a = String, G: 'a = u16> {
+    pub(crate) iter: Iter<'a, V, K, G>,
+}
+
+impl<'a, V, K, G> Iterator for Handles<'a, V, K, G>
+where
+    G: Generation,
+{
+    type Item = Handle<V, G>;
+
+    fn next(&mut self) -> Option<Handle<V, G>> {
+        self.iter.next().map(|(handle, _, _)| handle)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for Handles<'a, V, K, G> where G: Generation {}
+
+/// An iterator over the elements of a `HandleMap`, in slot order.
+pub struct Values<'a, V: 'a> {
+    pub(crate) slots: slice::Iter<'a, Option<V>>,
+}
+
+impl<'a, V> Iterator for Values<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.slots.by_ref().flatten().next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.slots.size_hint().1)
+    }
+}
+
+/// A mutable iterator over the elements of a `HandleMap`, in slot order.
+pub struct ValuesMut<'a, V: 'a> {
+    pub(crate) slots: slice::IterMut<'a, Option<V>>,
+}
+
+impl<'a, V> Iterator for ValuesMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.slots.by_ref().flatten().next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.slots.size_hint().1)
+    }
+}
+
+/// A draining iterator over the keys and elements of a `HandleMap`.
+///
+/// All handles to the drained elements become stale,
+/// even if the iterator is dropped before it is exhausted.
+pub struct Drain<'a, V: 'a, K: 'a + Hash + Eq = String, G: 'a + Generation = u16> {
+    pub(crate) index: usize,
+    pub(crate) map: &'a mut HandleMap<V, K, G>,
+}
+
+impl<'a, V, K, G> Iterator for Drain<'a, V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Item = (Option<K>, V);
+
+    fn next(&mut self) -> Option<(Option<K>, V)> {
+        while self.index < self.map.storage.len() {
+            let index = self.index;
+            self.index += 1;
+
+            if self.map.storage[index].is_some() {
+                let key = self.map.keys[index].take();
+
+                return Some((key, self.map.vacate(index)));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.map.len, Some(self.map.len))
+    }
+}
+
+impl<'a, V, K, G> ExactSizeIterator for Drain<'a, V, K, G> where K: Hash + Eq, G: Generation {}
+
+impl<'a, V, K, G> Drop for Drain<'a, V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn drop(&mut self) {
+        for _ in &mut *self {}
+    }
+}
+
+/// An owning iterator over the keys and elements of a `HandleMap`.
+pub struct IntoIter<V, K = String, G = u16> {
+    pub(crate) slots: Zip<vec::IntoIter<Option<K>>, vec::IntoIter<Option<V>>>,
+    pub(crate) remaining: usize,
+    pub(crate) marker: PhantomData<G>,
+}
+
+impl<V, K, G> Iterator for IntoIter<V, K, G> {
+    type Item = (Option<K>, V);
+
+    fn next(&mut self) -> Option<(Option<K>, V)> {
+        for (key, value) in &mut self.slots {
+            if let Some(value) = value {
+                self.remaining -= 1;
+
+                return Some((key, value));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<V, K, G> ExactSizeIterator for IntoIter<V, K, G> {}
+
+impl<V, K, G> IntoIterator for HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Item = (Option<K>, V);
+    type IntoIter = IntoIter<V, K, G>;
+
+    fn into_iter(self) -> IntoIter<V, K, G> {
+        IntoIter {
+            slots: self.keys.into_iter().zip(self.storage),
+            remaining: self.len,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, V, K, G> IntoIterator for &'a HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Item = (Handle<V, G>, Option<&'a K>, &'a V);
+    type IntoIter = Iter<'a, V, K, G>;
+
+    fn into_iter(self) -> Iter<'a, V, K, G> {
+        self.iter()
+    }
+}
+
+impl<'a, V, K, G> IntoIterator for &'a mut HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Item = (Handle<V, G>, Option<&'a K>, &'a mut V);
+    type IntoIter = IterMut<'a, V, K, G>;
+
+    fn into_iter(self) -> IterMut<'a, V, K, G> {
+        self.iter_mut()
+    }
+}
+
+impl<S, V, K, G> FromIterator<(S, V)> for HandleMap<V, K, G>
+where
+    S: Into<K>,
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (S, V)>,
+    {
+        let mut map = HandleMap::new();
+        map.extend(iter);
+
+        map
+    }
+}
+
+impl<S, V, K, G> Extend<(S, V)> for HandleMap<V, K, G>
+where
+    S: Into<K>,
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (S, V)>,
+    {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use HandleMap;
+
+    #[test]
+    fn iterate() {
+        let mut map: HandleMap<_> = vec![("one", 1), ("two", 2), ("three", 3)].into_iter().collect();
+
+        map.remove_key("two");
+
+        let mut entries: Vec<_> = map.iter().map(|(h, k, v)| (k.unwrap().to_string(), *v, map[h])).collect();
+        entries.sort();
+        assert_eq!(vec![("one".to_string(), 1, 1), ("three".to_string(), 3, 3)], entries);
+
+        for (_, key, value) in &mut map {
+            if key.map(String::as_str) == Some("one") {
+                *value = 10;
+            }
+        }
+
+        for value in map.values_mut() {
+            *value += 1;
+        }
+
+        let mut values: Vec<_> = map.values().cloned().collect();
+        values.sort();
+        assert_eq!(vec![4, 11], values);
+        assert_eq!(2, map.keys().count());
+        assert_eq!(2, map.handles().filter(|&h| map.contains(h)).count());
+    }
+
+    #[test]
+    fn drain_invalidates_handles() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let one_handle = map.insert("one", 1);
+        map.insert("two", 2);
+
+        let mut drained: Vec<_> = map.drain().collect();
+        drained.sort();
+
+        assert_eq!(vec![(Some("one".to_string()), 1), (Some("two".to_string()), 2)], drained);
+        assert!(map.is_empty());
+        assert!(!map.contains(one_handle));
+
+        map.extend(vec![("three", 3)]);
+
+        let owned: Vec<_> = map.into_iter().collect();
+        assert_eq!(vec![(Some("three".to_string()), 3)], owned);
+    }
+
+    #[test]
+    fn anonymous_elements() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let one = map.insert("one", 1);
+        let anonymous = map.insert_anonymous(2);
+
+        let entries: Vec<_> = map.iter().map(|(h, k, v)| (h, k.cloned(), *v)).collect();
+        assert_eq!(vec![(one, Some("one".to_string()), 1), (anonymous, None, 2)], entries);
+        assert_eq!(vec![one, anonymous], map.handles().collect::<Vec<_>>());
+        assert_eq!(1, map.keys().count());
+
+        let drained: Vec<_> = map.drain().collect();
+        assert_eq!(vec![(Some("one".to_string()), 1), (None, 2)], drained);
+        assert!(!map.contains(anonymous));
+    }
+}
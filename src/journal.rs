@@ -0,0 +1,800 @@
+//! A write-ahead journal for `HandleMap`s.
+//!
+//! A journal directory contains two files: `snapshot`, written with
+//! `HandleMap::write_snapshot`, and `journal`, which starts with the
+//! magic bytes `HMJL`, the format version (`u16`) and the checksum of
+//! the snapshot it applies to (`u64`), followed by one record per
+//! mutation. A record is its length (`u32`), an FNV-1a checksum of
+//! the length (`u32`), its body and an FNV-1a checksum of the body
+//! (`u64`). A torn record at the end of the journal is the result of
+//! a crash during the write and is dropped. A record is only taken
+//! for torn if it is the last one, any other invalid record makes
+//! `recover` fail with `JournalError::Corrupt`.
+
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use fnv::FnvHasher;
+
+use {AliasError, Codec, Generation, Handle, HandleError, HandleMap, RenameError, SnapshotError};
+
+const MAGIC: &[u8; 4] = b"HMJL";
+const VERSION: u16 = 2;
+const HEADER_LEN: u64 = 4 + 2 + 8;
+/// The length and its checksum.
+const RECORD_HEADER_LEN: usize = 4 + 4;
+const RECORD_OVERHEAD: usize = RECORD_HEADER_LEN + 8;
+
+const SNAPSHOT: &str = "snapshot";
+const JOURNAL: &str = "journal";
+
+const INSERT: u8 = 1;
+const REPLACE: u8 = 2;
+const REMOVE: u8 = 3;
+const INSERT_ANONYMOUS: u8 = 4;
+const ADD_ALIAS: u8 = 5;
+const REMOVE_ALIAS: u8 = 6;
+const RENAME: u8 = 7;
+const FORCE_RENAME: u8 = 8;
+
+/// When a `JournaledHandleMap` calls `fsync` on its journal.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SyncPolicy {
+    /// After every mutation. Nothing is lost on power failure.
+    Always,
+    /// After every `n` mutations.
+    Every(usize),
+    /// Only in `sync` and `compact`. Records are still handed to
+    /// the OS immediately, so a crash of the process loses nothing.
+    Never,
+}
+
+/// The reason a journal operation failed.
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// The snapshot could not be read.
+    Snapshot(SnapshotError),
+    /// The journal header or a record in the middle is invalid.
+    Corrupt(&'static str),
+    /// Replaying a record did not produce the handle it recorded.
+    Diverged,
+    /// The handle passed to a mutation is not alive.
+    Handle(HandleError),
+    /// The alias passed to `add_alias` belongs to a different element.
+    Alias(AliasError),
+    /// A key could not be renamed.
+    Rename(RenameError),
+    /// A failed write or compaction could not be undone, so the
+    /// journal no longer matches the map. Mutations fail until
+    /// `compact` succeeds.
+    Poisoned,
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<SnapshotError> for JournalError {
+    fn from(e: SnapshotError) -> Self {
+        JournalError::Snapshot(e)
+    }
+}
+
+impl From<HandleError> for JournalError {
+    fn from(e: HandleError) -> Self {
+        JournalError::Handle(e)
+    }
+}
+
+impl From<AliasError> for JournalError {
+    fn from(e: AliasError) -> Self {
+        match e {
+            AliasError::Handle(e) => JournalError::Handle(e),
+            e => JournalError::Alias(e),
+        }
+    }
+}
+
+impl From<RenameError> for JournalError {
+    fn from(e: RenameError) -> Self {
+        JournalError::Rename(e)
+    }
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JournalError::Io(ref e) => write!(f, "journal I/O failed: {}", e),
+            JournalError::Snapshot(ref e) => write!(f, "{}", e),
+            JournalError::Corrupt(reason) => write!(f, "the journal is corrupt: {}", reason),
+            JournalError::Diverged => write!(f, "replaying the journal produced different handles"),
+            JournalError::Handle(ref e) => write!(f, "{}", e),
+            JournalError::Alias(ref e) => write!(f, "{}", e),
+            JournalError::Rename(ref e) => write!(f, "{}", e),
+            JournalError::Poisoned => write!(f, "the journal is out of sync with the map"),
+        }
+    }
+}
+
+impl Error for JournalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            JournalError::Io(ref e) => Some(e),
+            JournalError::Snapshot(ref e) => Some(e),
+            JournalError::Handle(ref e) => Some(e),
+            JournalError::Alias(ref e) => Some(e),
+            JournalError::Rename(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A `HandleMap` that appends every mutation to a journal
+/// before applying it, so it can be recovered after a crash.
+///
+/// Dereferences to the underlying map for reading.
+pub struct JournaledHandleMap<V, K = String, G = u16> {
+    map: HandleMap<V, K, G>,
+    dir: PathBuf,
+    journal: File,
+    /// The length of the journal up to the last complete record.
+    len: u64,
+    /// Set if the journal may not match `map` anymore.
+    poisoned: bool,
+    sync: SyncPolicy,
+    unsynced: usize,
+}
+
+impl<V, K, G> JournaledHandleMap<V, K, G>
+where
+    V: Codec,
+    K: Codec + Hash + Eq + Clone,
+    G: Generation + Codec,
+{
+    /// Opens the journal directory `dir`, replaying its journal on
+    /// top of its snapshot. Creates an empty map if `dir` contains
+    /// no snapshot yet.
+    ///
+    /// The recovered map has exactly the handles and generations
+    /// the map had before.
+    pub fn recover<P: AsRef<Path>>(dir: P, sync: SyncPolicy) -> Result<Self, JournalError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let snapshot = match fs::read(dir.join(SNAPSHOT)) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return JournaledHandleMap::create(dir, HandleMap::new(), sync);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut map = HandleMap::read_snapshot(&snapshot[..])?;
+        let base = snapshot_checksum(&snapshot);
+
+        let mut journal = match OpenOptions::new().read(true).write(true).open(dir.join(JOURNAL)) {
+            Ok(journal) => journal,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return JournaledHandleMap::create(dir, map, sync);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        journal.read_to_end(&mut bytes)?;
+
+        let mut reader = &bytes[..];
+        let mut magic = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(JournalError::Corrupt("bad journal header"));
+        }
+        if u16::decode(&mut reader).ok() != Some(VERSION) {
+            return Err(JournalError::Corrupt("unsupported journal version"));
+        }
+        match u64::decode(&mut reader) {
+            Ok(checksum) if checksum == base => {}
+            // The previous compaction crashed after writing the snapshot,
+            // so the journal is already part of it.
+            Ok(_) => return JournaledHandleMap::create(dir, map, sync),
+            Err(_) => return Err(JournalError::Corrupt("bad journal header")),
+        }
+
+        let mut valid = HEADER_LEN;
+        while let Some((body, len)) = next_record(&mut reader)? {
+            replay(&mut map, body)?;
+            valid += len;
+        }
+
+        // Drop a torn record, so new ones are not appended after it.
+        journal.set_len(valid)?;
+        journal.seek(SeekFrom::End(0))?;
+
+        Ok(JournaledHandleMap {
+            map,
+            dir,
+            journal,
+            len: valid,
+            poisoned: false,
+            sync,
+            unsynced: 0,
+        })
+    }
+
+    /// Inserts a value under `key`, see `HandleMap::insert`.
+    pub fn insert<S>(&mut self, key: S, value: V) -> Result<Handle<V, G>, JournalError>
+    where
+        S: Into<K>,
+    {
+        let key = key.into();
+        let handle = self.map.next_handle();
+
+        let mut body = vec![INSERT];
+        encode_handle(handle, &mut body)?;
+        key.encode(&mut body)?;
+        value.encode(&mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.insert(key, value))
+    }
+
+    /// Inserts a value without a key, see `HandleMap::insert_anonymous`.
+    pub fn insert_anonymous(&mut self, value: V) -> Result<Handle<V, G>, JournalError> {
+        let handle = self.map.next_handle();
+
+        let mut body = vec![INSERT_ANONYMOUS];
+        encode_handle(handle, &mut body)?;
+        value.encode(&mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.insert_anonymous(value))
+    }
+
+    /// Replaces an element, see `HandleMap::replace`.
+    pub fn replace(&mut self, handle: Handle<V, G>, value: V) -> Result<V, JournalError> {
+        self.map.check(handle)?;
+
+        let mut body = vec![REPLACE];
+        encode_handle(handle, &mut body)?;
+        value.encode(&mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.try_replace(handle, value)?)
+    }
+
+    /// Removes an element, see `HandleMap::remove`.
+    pub fn remove(&mut self, handle: Handle<V, G>) -> Result<Option<V>, JournalError> {
+        if !self.map.contains(handle) {
+            return Ok(None);
+        }
+
+        let mut body = vec![REMOVE];
+        encode_handle(handle, &mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.remove(handle))
+    }
+
+    /// Removes the element stored under `key`.
+    pub fn remove_key<Q>(&mut self, key: &Q) -> Result<Option<V>, JournalError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match self.map.handle(key) {
+            Some(handle) => self.remove(handle),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds an alias, see `HandleMap::add_alias`.
+    pub fn add_alias<S>(&mut self, handle: Handle<V, G>, key: S) -> Result<(), JournalError>
+    where
+        S: Into<K>,
+    {
+        let key = key.into();
+        if !self.map.check_alias(handle, &key)? {
+            return Ok(());
+        }
+
+        let mut body = vec![ADD_ALIAS];
+        encode_handle(handle, &mut body)?;
+        key.encode(&mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.add_alias(handle, key)?)
+    }
+
+    /// Removes an alias, see `HandleMap::remove_alias`.
+    pub fn remove_alias<Q>(&mut self, key: &Q) -> Result<Option<Handle<V, G>>, JournalError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let alias = match self.map.handle(key) {
+            Some(handle) if self.map.key_of(handle).map(Borrow::borrow) != Some(key) => {
+                self.map.stored_key(handle, key).expect("Bug: key not in slot")
+            }
+            _ => return Ok(None),
+        };
+
+        let mut body = vec![REMOVE_ALIAS];
+        alias.encode(&mut body)?;
+        self.append(&body)?;
+
+        Ok(self.map.remove_alias(key))
+    }
+
+    /// Renames a key or alias, see `HandleMap::rename`.
+    pub fn rename<Q, S>(&mut self, old: &Q, new: S) -> Result<Handle<V, G>, JournalError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        S: Into<K>,
+    {
+        let new = new.into();
+        let handle = self.map.check_rename(old, &new)?;
+
+        self.append_rename(RENAME, handle, old, &new)?;
+
+        Ok(self.map.rename(old, new)?)
+    }
+
+    /// Renames a key or alias, removing the element that
+    /// had the new key, see `HandleMap::force_rename`.
+    pub fn force_rename<Q, S>(&mut self, old: &Q, new: S) -> Result<Handle<V, G>, JournalError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        S: Into<K>,
+    {
+        let new = new.into();
+        let handle = self.map.handle(old).ok_or(RenameError::NotFound)?;
+
+        self.append_rename(FORCE_RENAME, handle, old, &new)?;
+
+        Ok(self.map.force_rename(old, new)?)
+    }
+
+    /// Calls `fsync` on the journal.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.journal.sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Folds the journal into a new snapshot and starts an empty journal.
+    ///
+    /// If this fails, the journal may belong to an older snapshot and
+    /// the map is poisoned, see `JournalError::Poisoned`. A successful
+    /// compaction writes everything again and clears that.
+    pub fn compact(&mut self) -> Result<(), JournalError> {
+        self.poisoned = true;
+
+        self.journal = compact(&self.dir, &self.map)?;
+        self.len = HEADER_LEN;
+        self.poisoned = false;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Unwraps the map, closing the journal.
+    pub fn into_inner(self) -> HandleMap<V, K, G> {
+        self.map
+    }
+
+    fn create(dir: PathBuf, map: HandleMap<V, K, G>, sync: SyncPolicy) -> Result<Self, JournalError> {
+        Ok(JournaledHandleMap {
+            journal: compact(&dir, &map)?,
+            len: HEADER_LEN,
+            poisoned: false,
+            map,
+            dir,
+            sync,
+            unsynced: 0,
+        })
+    }
+
+    fn append_rename<Q>(&mut self, tag: u8, handle: Handle<V, G>, old: &Q, new: &K) -> Result<(), JournalError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut body = vec![tag];
+        self.map.stored_key(handle, old).expect("Bug: key not in slot").encode(&mut body)?;
+        new.encode(&mut body)?;
+
+        self.append(&body)
+    }
+
+    fn append(&mut self, body: &[u8]) -> Result<(), JournalError> {
+        if self.poisoned {
+            return Err(JournalError::Poisoned);
+        }
+
+        let mut record = Vec::with_capacity(body.len() + RECORD_OVERHEAD);
+        let len = body.len() as u32;
+        len.encode(&mut record)?;
+        len_checksum(len).encode(&mut record)?;
+        record.extend_from_slice(body);
+        checksum(body).encode(&mut record)?;
+
+        if let Err(e) = self.write_record(&record) {
+            // Cut off whatever made it to the file, as the mutation is
+            // not applied and later records must not follow a torn one.
+            let len = self.len;
+            if self.journal.set_len(len).and_then(|()| self.journal.seek(SeekFrom::End(0))).is_err() {
+                self.poisoned = true;
+            }
+
+            return Err(e.into());
+        }
+        self.len += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.journal.write_all(record)?;
+
+        self.unsynced += 1;
+        match self.sync {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<V, K, G> Deref for JournaledHandleMap<V, K, G> {
+    type Target = HandleMap<V, K, G>;
+
+    fn deref(&self) -> &HandleMap<V, K, G> {
+        &self.map
+    }
+}
+
+/// Writes a snapshot of `map` and an empty journal
+/// to `dir`, returning the journal opened for appending.
+fn compact<V, K, G>(dir: &Path, map: &HandleMap<V, K, G>) -> Result<File, JournalError>
+where
+    V: Codec,
+    K: Codec + Hash + Eq + Clone,
+    G: Generation + Codec,
+{
+    let mut snapshot = Vec::new();
+    map.write_snapshot(&mut snapshot)?;
+
+    write_file(&dir.join("snapshot.tmp"), &snapshot)?;
+    write_file(&dir.join("journal.tmp"), &header(snapshot_checksum(&snapshot)))?;
+
+    // Order matters: a journal is ignored if it does not
+    // belong to the snapshot, see `recover`.
+    fs::rename(dir.join("snapshot.tmp"), dir.join(SNAPSHOT))?;
+    fs::rename(dir.join("journal.tmp"), dir.join(JOURNAL))?;
+    sync_dir(dir)?;
+
+    Ok(OpenOptions::new().append(true).open(dir.join(JOURNAL))?)
+}
+
+fn replay<V, K, G>(map: &mut HandleMap<V, K, G>, mut body: &[u8]) -> Result<(), JournalError>
+where
+    V: Codec,
+    K: Codec + Hash + Eq + Clone,
+    G: Generation + Codec,
+{
+    let body = &mut body;
+
+    match u8::decode(body)? {
+        INSERT => {
+            let expected = decode_handle(map, body)?;
+            let key = K::decode(body)?;
+            let value = V::decode(body)?;
+
+            if map.insert(key, value) != expected {
+                return Err(JournalError::Diverged);
+            }
+        }
+        INSERT_ANONYMOUS => {
+            let expected = decode_handle(map, body)?;
+            let value = V::decode(body)?;
+
+            if map.insert_anonymous(value) != expected {
+                return Err(JournalError::Diverged);
+            }
+        }
+        ADD_ALIAS => {
+            let handle = decode_handle(map, body)?;
+            let key = K::decode(body)?;
+
+            map.add_alias(handle, key).map_err(|_| JournalError::Diverged)?;
+        }
+        REMOVE_ALIAS => {
+            let key = K::decode(body)?;
+
+            map.remove_alias(&key).ok_or(JournalError::Diverged)?;
+        }
+        RENAME => {
+            let old = K::decode(body)?;
+            let new = K::decode(body)?;
+
+            map.rename(&old, new).map_err(|_| JournalError::Diverged)?;
+        }
+        FORCE_RENAME => {
+            let old = K::decode(body)?;
+            let new = K::decode(body)?;
+
+            map.force_rename(&old, new).map_err(|_| JournalError::Diverged)?;
+        }
+        REPLACE => {
+            let handle = decode_handle(map, body)?;
+            let value = V::decode(body)?;
+
+            map.try_replace(handle, value).map_err(|_| JournalError::Diverged)?;
+        }
+        REMOVE => {
+            let handle = decode_handle(map, body)?;
+
+            map.remove(handle).ok_or(JournalError::Diverged)?;
+        }
+        _ => return Err(JournalError::Corrupt("unknown record")),
+    }
+
+    if !body.is_empty() {
+        return Err(JournalError::Corrupt("trailing bytes in record"));
+    }
+
+    Ok(())
+}
+
+/// Reads the next record, returning its body and its length on disk.
+///
+/// Returns `None` at the end of the journal or at a torn last record.
+fn next_record<'a>(reader: &mut &'a [u8]) -> Result<Option<(&'a [u8], u64)>, JournalError> {
+    if reader.len() < RECORD_HEADER_LEN {
+        // A partial write of the length, or nothing at all.
+        return Ok(None);
+    }
+
+    let mut rest = *reader;
+    let len = u32::decode(&mut rest)?;
+    if u32::decode(&mut rest)? != len_checksum(len) {
+        // Writes are not reordered within a record, so a complete
+        // header was written completely.
+        return Err(JournalError::Corrupt("record length checksum mismatch"));
+    }
+    let len = len as usize;
+    if rest.len() < len + 8 {
+        // The length is valid, so this is the last record, cut short.
+        return Ok(None);
+    }
+
+    let (body, mut rest) = rest.split_at(len);
+    let expected = u64::decode(&mut rest)?;
+    if checksum(body) != expected {
+        // Only the last record can be torn.
+        return if rest.is_empty() {
+            Ok(None)
+        } else {
+            Err(JournalError::Corrupt("record checksum mismatch"))
+        };
+    }
+
+    *reader = rest;
+
+    Ok(Some((body, (len + RECORD_OVERHEAD) as u64)))
+}
+
+fn encode_handle<V, G>(handle: Handle<V, G>, out: &mut Vec<u8>) -> io::Result<()>
+where
+    G: Generation + Codec,
+{
+    (handle.index as u64).encode(out)?;
+
+    handle.generation.encode(out)
+}
+
+fn decode_handle<V, K, G>(map: &HandleMap<V, K, G>, body: &mut &[u8]) -> Result<Handle<V, G>, JournalError>
+where
+    G: Generation + Codec,
+{
+    let index = u64::decode(body)? as usize;
+    let generation = G::decode(body)?;
+
+    Ok(Handle::new(index, generation, map.id.get()))
+}
+
+fn header(base: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&base.to_le_bytes());
+
+    header
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+
+    hasher.finish()
+}
+
+fn len_checksum(len: u32) -> u32 {
+    checksum(&len.to_le_bytes()) as u32
+}
+
+/// Returns the checksum a valid snapshot ends with.
+fn snapshot_checksum(snapshot: &[u8]) -> u64 {
+    let mut tail = &snapshot[snapshot.len() - 8..];
+
+    u64::decode(&mut tail).expect("Bug: snapshot too short")
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use {JournalError, JournaledHandleMap, SyncPolicy};
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = env::temp_dir().join(format!(
+            "handle-map-journal-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        dir
+    }
+
+    #[test]
+    fn recover_replays_journal() {
+        let dir = temp_dir();
+
+        let (one, two, three, four) = {
+            let mut map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+
+            let one = map.insert("one", "1".to_string()).unwrap();
+            let two = map.insert("two", "2".to_string()).unwrap();
+            map.compact().unwrap();
+
+            map.replace(one, "11".to_string()).unwrap();
+            map.remove(two).unwrap();
+            let three = map.insert("three", "3".to_string()).unwrap();
+            let four = map.insert_anonymous("4".to_string()).unwrap();
+            map.add_alias(three, "drei").unwrap();
+            map.add_alias(three, "trois").unwrap();
+            map.remove_alias("trois").unwrap();
+            map.rename("drei", "tres").unwrap();
+
+            (one, two, three, four)
+        };
+
+        let map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Always).unwrap();
+        let one_now = map.handle("one").unwrap();
+
+        assert!(!map.contains(one));
+        assert!(!map.contains(two));
+        assert_eq!("11", map[one_now]);
+        assert_eq!("3", map[three]);
+        assert_eq!("4", map[four]);
+        assert_eq!(&["tres".to_string()], map.aliases(three));
+        assert_eq!(None, map.key_of(four));
+        assert_eq!(two.index(), three.index());
+        assert_eq!(3, map.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_record_is_dropped() {
+        let dir = temp_dir();
+
+        let one = {
+            let mut map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Every(2)).unwrap();
+
+            map.insert("one", "1".to_string()).unwrap()
+        };
+
+        let mut journal = OpenOptions::new().append(true).open(dir.join("journal")).unwrap();
+        journal.write_all(&[40, 0, 0, 0, 1, 2]).unwrap();
+
+        {
+            let mut map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+
+            assert_eq!("1", map[one]);
+            map.insert("two", "2".to_string()).unwrap();
+        }
+
+        let map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(2, map.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_length_is_not_torn() {
+        let dir = temp_dir();
+
+        {
+            let mut map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+
+            map.insert("one", "1".to_string()).unwrap();
+            map.insert("two", "2".to_string()).unwrap();
+            map.insert("three", "3".to_string()).unwrap();
+        }
+
+        let path = dir.join("journal");
+        let mut bytes = fs::read(&path).unwrap();
+        let first = u32::from_le_bytes([bytes[14], bytes[15], bytes[16], bytes[17]]) as usize;
+        // The length of the second record, claiming more than is left.
+        bytes[14 + 16 + first + 3] ^= 0x10;
+        fs::write(&path, &bytes).unwrap();
+
+        match JournaledHandleMap::<String>::recover(&dir, SyncPolicy::Never) {
+            Err(JournalError::Corrupt(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|map| map.len())),
+        }
+        assert_eq!(bytes.len() as u64, fs::metadata(&path).unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_compaction_poisons() {
+        let dir = temp_dir();
+
+        let mut map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+        map.insert("one", "1".to_string()).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(map.compact().is_err());
+        match map.insert("two", "2".to_string()) {
+            Err(JournalError::Poisoned) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(1, map.len());
+
+        fs::create_dir(&dir).unwrap();
+        map.compact().unwrap();
+        map.insert("two", "2".to_string()).unwrap();
+        drop(map);
+
+        let map: JournaledHandleMap<String> = JournaledHandleMap::recover(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(2, map.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,73 +1,538 @@
 extern crate fnv;
+#[cfg(all(test, loom))]
+extern crate loom;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 use fnv::FnvHashMap;
 
-#[derive(Default)]
-pub struct HandleMap<V> {
-    generations: Vec<Generation>,
-    keys_to_indices: FnvHashMap<String, Handle>,
-    storage: Vec<V>,
+pub use concurrent::ConcurrentHandleMap;
+pub use dense::{DenseHandleMap, DenseIter};
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use generation::{Generation, OverflowPolicy};
+pub use handle::Handle;
+#[cfg(feature = "hot-reload")]
+pub use hot_reload::ReloadEvent;
+pub use journal::{JournalError, JournaledHandleMap, SyncPolicy};
+pub use loader::{LoadError, Loader, LoadingHandleMap};
+pub use iter::{Drain, Handles, IntoIter, Iter, IterMut, Keys, Values, ValuesMut};
+pub use packed::PackedHandle;
+pub use pending::{completion_channel, Completer, Completions, LoadState};
+pub use path::{Glob, PathHandleMap, Prefix};
+pub use secondary::{SecondaryHandleMap, SecondaryIter, SparseSecondaryHandleMap, SparseSecondaryIter};
+pub use snapshot::{Codec, SnapshotError};
+pub use stable::StableHandleMap;
+pub use strong::StrongHandle;
+
+use allocator::SlotAllocator;
+use handle::OwnedMapId;
+
+mod allocator;
+mod concurrent;
+mod dense;
+mod entry;
+mod generation;
+mod handle;
+#[cfg(feature = "hot-reload")]
+mod hot_reload;
+mod iter;
+mod journal;
+mod loader;
+mod packed;
+mod path;
+mod pending;
+mod secondary;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod snapshot;
+mod stable;
+mod strong;
+
+pub struct HandleMap<V, K = String, G = u16> {
+    allocator: SlotAllocator<G>,
+    keys_to_indices: FnvHashMap<K, Handle<V, G>>,
+    /// The key of every slot, indexed like `storage`.
+    keys: Vec<Option<K>>,
+    /// The aliases of every slot, indexed like `storage`.
+    aliases: Vec<Vec<K>>,
+    storage: Vec<Option<V>>,
+    /// The reference counts of the slots that have strong handles.
+    strong: FnvHashMap<usize, Arc<()>>,
+    /// The states of the slots that are pending or failed.
+    pending: FnvHashMap<usize, LoadState>,
+    /// No slot at or above this index is occupied,
+    /// so `pop` does not rescan trailing vacant slots.
+    top: usize,
+    len: usize,
+    id: OwnedMapId,
 }
 
-impl<V> HandleMap<V> {
+impl<V, K, G> HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
     #[inline]
     pub fn new() -> Self {
         HandleMap {
-            generations: Vec::new(),
+            allocator: SlotAllocator::new(),
             keys_to_indices: Default::default(),
+            keys: Vec::new(),
+            aliases: Vec::new(),
             storage: Vec::new(),
+            strong: Default::default(),
+            pending: Default::default(),
+            top: 0,
+            len: 0,
+            id: OwnedMapId::next(),
         }
     }
 
     #[inline]
     pub fn with_capacity(capacity: usize) -> Self {
         HandleMap {
-            generations: Vec::new(),
+            allocator: SlotAllocator::with_capacity(capacity),
             keys_to_indices: FnvHashMap::with_capacity_and_hasher(capacity, Default::default()),
+            keys: Vec::with_capacity(capacity),
+            aliases: Vec::with_capacity(capacity),
             storage: Vec::with_capacity(capacity),
+            strong: Default::default(),
+            pending: Default::default(),
+            top: 0,
+            len: 0,
+            id: OwnedMapId::next(),
         }
     }
 
-    pub fn handle<S: Borrow<String>>(&self, key: S) -> Option<Handle> {
-        self.keys_to_indices.get(key.borrow()).map(|x| *x)
+    /// Returns the handle stored under `key` or under the alias `key`.
+    pub fn handle<Q>(&self, key: &Q) -> Option<Handle<V, G>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.keys_to_indices.get(key).copied()
     }
 
-    pub fn insert<S>(&mut self, key: S, value: V) -> Handle where S: Into<String> {
-        let index = self.storage.len();
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-        let generation = self.bump_gen(index);
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        let index = Handle {
-            index: index,
-            generation: generation
-        };
+    #[inline]
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.allocator.overflow()
+    }
+
+    /// Sets what happens once a slot has been reused
+    /// so often that its generation overflows.
+    ///
+    /// Defaults to `OverflowPolicy::Retire`.
+    #[inline]
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.allocator.set_overflow(policy);
+    }
+
+    /// Returns `true` if `handle` points to a live element of this map.
+    #[inline]
+    pub fn contains(&self, handle: Handle<V, G>) -> bool {
+        self.check(handle).is_ok()
+    }
+
+    /// Returns a reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get(&self, handle: Handle<V, G>) -> Option<&V> {
+        self.check(handle).ok()?;
+
+        self.storage[handle.index].as_ref()
+    }
+
+    /// Returns a mutable reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get_mut(&mut self, handle: Handle<V, G>) -> Option<&mut V> {
+        self.check(handle).ok()?;
+
+        self.storage[handle.index].as_mut()
+    }
+
+    /// Returns an iterator over all handles, keys and elements,
+    /// in slot order.
+    ///
+    /// The key is `None` for elements inserted with `insert_anonymous`.
+    pub fn iter(&self) -> Iter<'_, V, K, G> {
+        Iter {
+            slots: self.storage.iter().enumerate(),
+            generations: self.allocator.generations(),
+            keys: &self.keys,
+            map: self.id.get(),
+            remaining: self.len,
+        }
+    }
+
+    /// Returns an iterator over all handles, keys and mutable elements,
+    /// in slot order.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K, G> {
+        IterMut {
+            slots: self.storage.iter_mut().enumerate(),
+            generations: self.allocator.generations(),
+            keys: &self.keys,
+            map: self.id.get(),
+            remaining: self.len,
+        }
+    }
+
+    /// Returns an iterator over all keys, in arbitrary order.
+    ///
+    /// Anonymous elements have no key and are skipped.
+    pub fn keys(&self) -> Keys<'_, V, K, G> {
+        Keys {
+            keys: self.keys_to_indices.keys(),
+        }
+    }
+
+    /// Returns an iterator over all handles, in slot order.
+    pub fn handles(&self) -> Handles<'_, V, K, G> {
+        Handles { iter: self.iter() }
+    }
+
+    /// Returns an iterator over all elements, in slot order.
+    pub fn values(&self) -> Values<'_, V> {
+        Values {
+            slots: self.storage.iter(),
+        }
+    }
+
+    /// Returns an iterator over all mutable elements, in slot order.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V> {
+        ValuesMut {
+            slots: self.storage.iter_mut(),
+        }
+    }
+
+    /// Removes all elements, returning them together with their keys.
+    ///
+    /// Every handle into the map becomes stale.
+    pub fn drain(&mut self) -> Drain<'_, V, K, G> {
+        self.keys_to_indices.clear();
+
+        Drain { index: 0, map: self }
+    }
+
+    /// Gets the entry for `key`, which allows inserting
+    /// an element only if there is none yet.
+    pub fn entry<S>(&mut self, key: S) -> Entry<'_, V, K, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+
+        match self.keys_to_indices.get(&key).copied() {
+            Some(handle) => Entry::Occupied(OccupiedEntry {
+                key,
+                handle,
+                map: self,
+            }),
+            None => Entry::Vacant(VacantEntry { key, map: self }),
+        }
+    }
+
+    /// Inserts a value under `key`, reusing a previously
+    /// freed slot if there is one.
+    ///
+    /// If there already was an element stored under `key`,
+    /// it is removed and all handles to it become stale.
+    /// If `key` was an alias of an element, only the alias
+    /// is removed from it. Use `entry` to keep the existing
+    /// element instead.
+    pub fn insert<S>(&mut self, key: S, value: V) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+        let handle = self.push(value);
+
+        self.release_key(&key);
+        self.keys[handle.index] = Some(key.clone());
+        self.keys_to_indices.insert(key, handle);
+
+        handle
+    }
+
+    /// Inserts a value without a key, reusing a previously
+    /// freed slot if there is one.
+    ///
+    /// The element can only be reached through its handle.
+    pub fn insert_anonymous(&mut self, value: V) -> Handle<V, G> {
+        self.push(value)
+    }
+
+    /// Returns the key of the element `handle` points to,
+    /// or `None` if it is dead or anonymous.
+    ///
+    /// This is the key the element was inserted under,
+    /// see `aliases` for its other keys.
+    pub fn key_of(&self, handle: Handle<V, G>) -> Option<&K> {
+        self.check(handle).ok()?;
+
+        self.keys[handle.index].as_ref()
+    }
+
+    /// Adds `key` as another key of the element `handle` points to,
+    /// so that `handle(&key)` returns `handle`.
+    ///
+    /// Fails if `key` already belongs to a different element.
+    /// Adding a key the element already has does nothing.
+    pub fn add_alias<S>(&mut self, handle: Handle<V, G>, key: S) -> Result<(), AliasError>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+
+        if self.check_alias(handle, &key)? {
+            self.aliases[handle.index].push(key.clone());
+            self.keys_to_indices.insert(key, handle);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the alias `key`, returning the handle
+    /// of the element it belonged to.
+    ///
+    /// Returns `None` if `key` is not an alias. The key an element
+    /// was inserted under cannot be removed this way.
+    pub fn remove_alias<Q>(&mut self, key: &Q) -> Option<Handle<V, G>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let handle = self.handle(key)?;
+        let aliases = &mut self.aliases[handle.index];
+
+        let position = aliases.iter().position(|alias| alias.borrow() == key)?;
+        aliases.remove(position);
+        self.keys_to_indices.remove(key);
+
+        Some(handle)
+    }
+
+    /// Returns the aliases of the element `handle` points to,
+    /// in the order they were added.
+    ///
+    /// Returns an empty slice if the handle is dead.
+    pub fn aliases(&self, handle: Handle<V, G>) -> &[K] {
+        match self.check(handle) {
+            Ok(()) => &self.aliases[handle.index],
+            Err(_) => &[],
+        }
+    }
+
+    /// Renames the key or alias `old` to `new`, keeping the handle
+    /// of the element valid.
+    ///
+    /// Fails if there is no element under `old` or
+    /// if `new` already belongs to a different element.
+    pub fn rename<Q, S>(&mut self, old: &Q, new: S) -> Result<Handle<V, G>, RenameError>
+    where
+        K: Borrow<Q> + Clone,
+        Q: ?Sized + Hash + Eq,
+        S: Into<K>,
+    {
+        let new = new.into();
+        let handle = self.check_rename(old, &new)?;
+
+        self.rebind(handle, old, new);
 
-        self.storage.push(value);
-        self.keys_to_indices.insert(key.into(), index);
+        Ok(handle)
+    }
 
-        index
+    /// Like `rename`, but if `new` already belongs to a different
+    /// element, it is taken from it, just like `insert` does: the
+    /// element is removed if `new` is its key, otherwise only
+    /// its alias `new` is.
+    pub fn force_rename<Q, S>(&mut self, old: &Q, new: S) -> Result<Handle<V, G>, RenameError>
+    where
+        K: Borrow<Q> + Clone,
+        Q: ?Sized + Hash + Eq,
+        S: Into<K>,
+    {
+        let new = new.into();
+        let handle = self.handle(old).ok_or(RenameError::NotFound)?;
+
+        if self.handle::<K>(&new).is_some_and(|owner| owner != handle) {
+            self.release_key(&new);
+        }
+        self.rebind(handle, old, new);
+
+        Ok(handle)
+    }
+
+    /// Makes `key` unused, removing the element whose key it is,
+    /// or just the alias `key` of an element.
+    fn release_key(&mut self, key: &K) {
+        if let Some(owner) = self.handle(key) {
+            if self.remove_alias(key).is_none() {
+                self.remove(owner);
+            }
+        }
     }
 
+    /// Checks whether `old` can be renamed to `new`,
+    /// returning the handle of the element under `old`.
+    fn check_rename<Q>(&self, old: &Q, new: &K) -> Result<Handle<V, G>, RenameError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let handle = self.handle(old).ok_or(RenameError::NotFound)?;
+
+        match self.handle::<K>(new) {
+            Some(owner) if owner != handle => Err(RenameError::Taken),
+            _ => Ok(handle),
+        }
+    }
+
+    /// Replaces the key or alias `old` of the element `handle` points to
+    /// with `new`, which must not belong to a different element.
+    fn rebind<Q>(&mut self, handle: Handle<V, G>, old: &Q, new: K)
+    where
+        K: Borrow<Q> + Clone,
+        Q: ?Sized + Hash + Eq,
+    {
+        if new.borrow() == old {
+            return;
+        }
+
+        let index = handle.index;
+        self.keys_to_indices.remove(old);
+        // `new` might already be an alias of the element.
+        self.aliases[index].retain(|alias| *alias != new);
+
+        match self.keys[index] {
+            Some(ref mut key) if (*key).borrow() == old => *key = new.clone(),
+            Some(ref key) if *key == new => self.aliases[index].retain(|alias| alias.borrow() != old),
+            _ => {
+                let alias = self.aliases[index]
+                    .iter_mut()
+                    .find(|alias| (**alias).borrow() == old)
+                    .expect("Bug: renamed key not in slot");
+                *alias = new.clone();
+            }
+        }
+
+        self.keys_to_indices.insert(new, handle);
+    }
+
+    /// Returns the stored key or alias of `handle` that is equal to `key`.
+    fn stored_key<Q>(&self, handle: Handle<V, G>, key: &Q) -> Option<&K>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.keys[handle.index]
+            .iter()
+            .chain(&self.aliases[handle.index])
+            .find(|stored| (*stored).borrow() == key)
+    }
+
+    /// Checks whether `key` can be added as an alias of `handle`.
+    ///
+    /// Returns `false` if `handle` already has that key.
+    fn check_alias(&self, handle: Handle<V, G>, key: &K) -> Result<bool, AliasError> {
+        self.check(handle)?;
+
+        match self.handle(key) {
+            Some(owner) if owner == handle => Ok(false),
+            Some(_) => Err(AliasError::Taken),
+            None => Ok(true),
+        }
+    }
+
+    /// Returns the handle the next inserted element will get.
+    fn next_handle(&self) -> Handle<V, G> {
+        let (index, generation) = self.allocator.peek();
+
+        Handle::new(index, generation, self.id.get())
+    }
+
+    /// Stores `value` in a free slot without assigning it a key.
+    fn push(&mut self, value: V) -> Handle<V, G> {
+        let index = self.allocator.alloc();
+        if index == self.storage.len() {
+            self.storage.push(None);
+            self.keys.push(None);
+            self.aliases.push(Vec::new());
+        }
+
+        self.storage[index] = Some(value);
+        self.len += 1;
+        self.top = self.top.max(index + 1);
+
+        self.handle_at(index)
+    }
+
+    /// Removes the element with the highest index.
     pub fn pop(&mut self) -> Option<V> {
-        if let Some(value) = self.storage.pop() {
-            let index = self.storage.len();
+        let index = self.last_index()?;
+        let handle = self.handle_at(index);
 
-            let key = self.keys_to_indices
-                .iter()
-                .find(|&(_, ref v)| v.index == index)
-                .expect("Bug: No such key in the HashMap")
-                .0
-                .clone();
+        self.remove(handle)
+    }
 
-            self.keys_to_indices.remove(&key);
+    /// Returns the index of the occupied slot with the highest index.
+    ///
+    /// Only scans the slots vacated since the last call.
+    fn last_index(&mut self) -> Option<usize> {
+        let last = self.storage[..self.top].iter().rposition(Option::is_some);
+        self.top = last.map_or(0, |index| index + 1);
 
-            Some(value)
-        } else {
-            None
+        last
+    }
+
+    /// Removes the element `handle` points to, freeing its slot
+    /// and invalidating all handles to it. Its key and all of
+    /// its aliases are removed as well.
+    ///
+    /// Returns `None` if the element was already removed.
+    pub fn remove(&mut self, handle: Handle<V, G>) -> Option<V> {
+        self.check(handle).ok()?;
+
+        if let Some(key) = self.keys[handle.index].take() {
+            self.keys_to_indices.remove(&key);
+        }
+        for alias in self.aliases[handle.index].drain(..) {
+            self.keys_to_indices.remove(&alias);
         }
+
+        Some(self.vacate(handle.index))
+    }
+
+    /// Removes the element stored under `key` or under the alias `key`.
+    pub fn remove_key<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let handle = self.handle(key)?;
+
+        self.remove(handle)
     }
 
     /// Removes an element and inserts a new one,
@@ -75,77 +540,302 @@ impl<V> HandleMap<V> {
     ///
     /// If you just want to mutate an element,
     /// use `IndexMut` instead.
-    pub fn replace(&mut self, index: Handle, value: V) -> V {
-        use std::mem::replace;
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not alive, see `try_replace`
+    /// for a non-panicking version.
+    pub fn replace(&mut self, index: Handle<V, G>, value: V) -> V {
+        match self.try_replace(index, value) {
+            Ok(value) => value,
+            Err(e) => panic!("Tried to use invalid handle: {}", e),
+        }
+    }
 
-        self.assert_alive(index);
+    /// Like `replace`, but returns an error instead of
+    /// panicking if `index` is not alive.
+    pub fn try_replace(&mut self, index: Handle<V, G>, value: V) -> Result<V, HandleError> {
+        self.check(index)?;
 
         let index = index.index;
+        // Strong handles become stale just like all other handles.
+        self.strong.remove(&index);
+        self.pending.remove(&index);
 
-        let value = replace(&mut self.storage[index], value);
-        self.bump_gen(index);
+        let (old, handle) = if self.allocator.bump(index) {
+            (self.storage[index].replace(value), self.handle_at(index))
+        } else {
+            // The slot is retired, move the new element somewhere else.
+            let old = self.storage[index].take();
+            self.len -= 1;
 
-        value
+            let handle = self.push(value);
+            self.keys[handle.index] = self.keys[index].take();
+            self.aliases.swap(index, handle.index);
+
+            (old, handle)
+        };
+
+        for key in self.keys[handle.index].iter().chain(&self.aliases[handle.index]) {
+            *self.keys_to_indices.get_mut(key).expect("Bug: slot key not in map") = handle;
+        }
+
+        Ok(old.expect("Bug: replaced an empty slot"))
+    }
+
+    /// Returns a handle to the current generation of the slot at `index`.
+    fn handle_at(&self, index: usize) -> Handle<V, G> {
+        Handle::new(index, self.allocator.generation(index), self.id.get())
     }
 
-    fn assert_alive(&self, index: Handle) {
-        if index.generation != self.generations[index.index] {
-            panic!("Tried to use dead index (the element was removed)");
+    /// Checks whether `index` points to a live element of this map.
+    fn check(&self, index: Handle<V, G>) -> Result<(), HandleError> {
+        if index.map != self.id.get() {
+            return Err(HandleError::WrongMap);
         }
+
+        self.allocator.check(index.index, index.generation, |slot| self.storage[slot].is_some())
     }
 
-    fn bump_gen(&mut self, index: usize) -> Generation {
-        if self.generations.len() > index {
-            self.generations[index] += 1;
+    fn assert_alive(&self, index: Handle<V, G>) {
+        if let Err(e) = self.check(index) {
+            panic!("Tried to use invalid handle: {}", e);
+        }
+    }
 
-            self.generations[index]
-        } else {
-            self.generations.push(0);
+    /// Takes the value out of the slot at `index`
+    /// and puts the slot on the free list, unless it is retired.
+    ///
+    /// Does not touch `keys_to_indices`.
+    fn vacate(&mut self, index: usize) -> V {
+        let value = self.storage[index].take().expect("Bug: vacated an empty slot");
+        self.keys[index] = None;
+        self.aliases[index].clear();
+        self.strong.remove(&index);
+        self.pending.remove(&index);
+        self.len -= 1;
+        self.allocator.release(index);
+
+        value
+    }
+}
+
+impl<V, K, G> HandleMap<V, K, G>
+where
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    /// Rebuilds a map from its slots, as used by snapshots
+    /// and deserialization. Validates everything that
+    /// cannot be trusted, returning a description of the
+    /// first problem found.
+    fn from_slots(
+        generations: Vec<G>,
+        keys: Vec<Option<K>>,
+        aliases: Vec<Vec<K>>,
+        storage: Vec<Option<V>>,
+        free: Vec<usize>,
+        overflow: OverflowPolicy,
+        id: OwnedMapId,
+    ) -> Result<Self, &'static str> {
+        let slots = storage.len();
+        if generations.len() != slots || keys.len() != slots || aliases.len() != slots {
+            return Err("slot vectors differ in length");
+        }
+        if generations.iter().any(|&g| g < G::FIRST) {
+            return Err("invalid generation");
+        }
+
+        let mut is_free = vec![false; slots];
+        for &index in &free {
+            if index >= slots || storage[index].is_some() || is_free[index] {
+                return Err("invalid free list");
+            }
+            is_free[index] = true;
+        }
+
+        let mut keys_to_indices = FnvHashMap::default();
+        for index in 0..slots {
+            for key in keys[index].iter().chain(&aliases[index]) {
+                if storage[index].is_none() {
+                    return Err("key of a vacant slot");
+                }
 
-            0
+                let handle = Handle::new(index, generations[index], id.get());
+                if keys_to_indices.insert(key.clone(), handle).is_some() {
+                    return Err("duplicate key");
+                }
+            }
         }
+
+        Ok(HandleMap {
+            len: storage.iter().filter(|v| v.is_some()).count(),
+            top: storage.iter().rposition(Option::is_some).map_or(0, |index| index + 1),
+            allocator: SlotAllocator::from_parts(generations, free, overflow),
+            keys_to_indices,
+            keys,
+            aliases,
+            storage,
+            strong: Default::default(),
+            pending: Default::default(),
+            id,
+        })
     }
 }
 
-impl<V> Index<Handle> for HandleMap<V> {
+impl<V, K> HandleMap<V, K, u32>
+where
+    K: Hash + Eq,
+{
+    /// Turns a packed handle back into a handle to this map.
+    pub fn unpack(&self, handle: PackedHandle<V>) -> Handle<V, u32> {
+        Handle::new(handle.index(), handle.generation(), self.id.get())
+    }
+}
+
+impl<V, K, G> Default for HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn default() -> Self {
+        HandleMap::new()
+    }
+}
+
+impl<V, K, G> Index<Handle<V, G>> for HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
     type Output = V;
 
-    fn index(&self, index: Handle) -> &V {
+    fn index(&self, index: Handle<V, G>) -> &V {
         self.assert_alive(index);
 
-        &self.storage[index.index]
+        self.storage[index.index].as_ref().unwrap()
     }
 }
 
-impl<V> IndexMut<Handle> for HandleMap<V> {
-    fn index_mut(&mut self, index: Handle) -> &mut V {
+impl<V, K, G> IndexMut<Handle<V, G>> for HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn index_mut(&mut self, index: Handle<V, G>) -> &mut V {
         self.assert_alive(index);
 
-        &mut self.storage[index.index]
+        self.storage[index.index].as_mut().unwrap()
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct Handle {
-    index: usize,
-    generation: Generation,
+/// The reason a `Handle` could not be used with a `HandleMap`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum HandleError {
+    /// The element the handle pointed to was removed or replaced.
+    Stale,
+    /// The handle points past the end of the map.
+    OutOfBounds,
+    /// The handle was created by a different map.
+    WrongMap,
 }
 
-impl Handle {
-    pub fn index(&self) -> usize {
-        self.index
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandleError::Stale => write!(f, "the element was removed"),
+            HandleError::OutOfBounds => write!(f, "the handle is out of bounds"),
+            HandleError::WrongMap => write!(f, "the handle belongs to a different map"),
+        }
+    }
+}
+
+impl Error for HandleError {}
+
+/// The reason a key could not be added as an alias.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AliasError {
+    /// The handle is not alive.
+    Handle(HandleError),
+    /// The key already belongs to a different element.
+    Taken,
+}
+
+/// The reason a key could not be renamed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RenameError {
+    /// There is no element under the old key.
+    NotFound,
+    /// The new key already belongs to a different element.
+    Taken,
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenameError::NotFound => write!(f, "there is no element under the key"),
+            RenameError::Taken => write!(f, "the new key belongs to a different element"),
+        }
     }
 }
 
-type Generation = u16;
+impl Error for RenameError {}
+
+impl From<HandleError> for AliasError {
+    fn from(e: HandleError) -> Self {
+        AliasError::Handle(e)
+    }
+}
+
+impl fmt::Display for AliasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AliasError::Handle(ref e) => write!(f, "{}", e),
+            AliasError::Taken => write!(f, "the key belongs to a different element"),
+        }
+    }
+}
+
+impl Error for AliasError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            AliasError::Handle(ref e) => Some(e),
+            AliasError::Taken => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use super::{HandleMap};
+    use super::{AliasError, HandleError, HandleMap, RenameError};
+
+    #[test]
+    fn pop_skips_vacant_slots() {
+        let mut map: HandleMap<usize> = HandleMap::new();
+
+        let handles: Vec<_> = (0..100).map(|i| map.insert_anonymous(i)).collect();
+        for &handle in &handles[50..] {
+            map.remove(handle);
+        }
+        assert_eq!(100, map.top);
+
+        assert_eq!(Some(49), map.pop());
+        assert_eq!(50, map.top);
+        assert_eq!(Some(48), map.pop());
+        assert_eq!(49, map.top);
+
+        let reused = map.insert_anonymous(100);
+        assert_eq!(reused.index() + 1, map.top);
+        assert_eq!(Some(100), map.pop());
+        assert_eq!(Some(47), map.pop());
+        while map.pop().is_some() {}
+        assert_eq!(0, map.top);
+        assert!(map.is_empty());
+    }
 
     #[test]
     fn insert_and_get() {
-        let mut map = HandleMap::new();
+        let mut map: HandleMap<_> = HandleMap::new();
 
         let one_handle = map.insert("one", 1);
         let five_handle = map.insert("five", 5);
@@ -157,7 +847,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn generation_invalid() {
-        let mut map = HandleMap::new();
+        let mut map: HandleMap<_> = HandleMap::new();
 
         map.insert("one", 1);
         let five_handle = map.insert("five", 5);
@@ -166,6 +856,233 @@ mod tests {
 
         map.insert("four", 4);
 
-        map[five_handle];
+        let _ = map[five_handle];
+    }
+
+    #[test]
+    fn remove_reuses_slot() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+        let two_handle = map.insert("two", 2);
+
+        assert_eq!(Some(1), map.remove(one_handle));
+        assert_eq!(None, map.remove(one_handle));
+        assert_eq!(None, map.handle("one"));
+        assert_eq!(1, map.len());
+
+        let three_handle = map.insert("three", 3);
+
+        assert_eq!(one_handle.index(), three_handle.index());
+        assert_ne!(one_handle, three_handle);
+        assert_eq!(2, map[two_handle]);
+        assert_eq!(3, map[three_handle]);
+    }
+
+    #[test]
+    fn remove_key() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+
+        assert_eq!(Some(1), map.remove_key("one"));
+        assert_eq!(None, map.remove_key("one"));
+        assert!(map.is_empty());
+
+        map.insert("two", 2);
+
+        assert_eq!(None, map.remove(one_handle));
+    }
+
+    #[test]
+    #[should_panic]
+    fn removed_handle_panics() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+        map.remove(one_handle);
+        map.insert("two", 2);
+
+        let _ = map[one_handle];
+    }
+
+    #[test]
+    fn get_and_contains() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+
+        assert!(map.contains(one_handle));
+        assert_eq!(Some(&1), map.get(one_handle));
+
+        *map.get_mut(one_handle).unwrap() += 1;
+        assert_eq!(2, map[one_handle]);
+
+        map.remove(one_handle);
+
+        assert!(!map.contains(one_handle));
+        assert_eq!(None, map.get(one_handle));
+        assert_eq!(None, map.get_mut(one_handle));
+    }
+
+    #[test]
+    fn insert_existing_key() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let old_handle = map.insert("one", 1);
+        let new_handle = map.insert("one", 11);
+
+        assert_eq!(1, map.len());
+        assert!(!map.contains(old_handle));
+        assert_eq!(Some(new_handle), map.handle("one"));
+        assert_eq!(11, map[new_handle]);
+    }
+
+    #[test]
+    fn key_of() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+        let two_handle = map.insert("two", 2);
+
+        assert_eq!(Some(&"one".to_string()), map.key_of(one_handle));
+        assert_eq!(Some(&"two".to_string()), map.key_of(two_handle));
+
+        map.replace(one_handle, 11);
+        assert_eq!(None, map.key_of(one_handle));
+        assert_eq!(Some(&"one".to_string()), map.key_of(map.handle("one").unwrap()));
+
+        assert_eq!(Some(2), map.pop());
+        assert_eq!(None, map.handle("two"));
+        assert_eq!(None, map.key_of(two_handle));
+    }
+
+    #[test]
+    fn anonymous() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+        let anonymous = map.insert_anonymous(2);
+
+        assert_eq!(2, map.len());
+        assert_eq!(2, map[anonymous]);
+        assert_eq!(None, map.key_of(anonymous));
+        assert_eq!(Some(&"one".to_string()), map.key_of(one_handle));
+
+        map.replace(anonymous, 3);
+        assert_eq!(Some(3), map.pop());
+        assert_eq!(Some(one_handle), map.handle("one"));
+        assert_eq!(1, map.len());
+    }
+
+    #[test]
+    fn aliases() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let texture = map.insert("textures/stone.png", 1);
+        let other = map.insert("other", 2);
+
+        assert_eq!(Ok(()), map.add_alias(texture, "5f3a"));
+        assert_eq!(Ok(()), map.add_alias(texture, "stone"));
+        assert_eq!(Ok(()), map.add_alias(texture, "stone"));
+        assert_eq!(Err(AliasError::Taken), map.add_alias(texture, "other"));
+        assert_eq!(Some(texture), map.handle("stone"));
+        assert_eq!(&["5f3a".to_string(), "stone".to_string()], map.aliases(texture));
+
+        assert_eq!(None, map.remove_alias("textures/stone.png"));
+        assert_eq!(Some(texture), map.remove_alias("5f3a"));
+        assert_eq!(None, map.handle("5f3a"));
+
+        map.replace(texture, 11);
+        let texture = map.handle("stone").unwrap();
+        assert_eq!(Some(texture), map.handle("textures/stone.png"));
+
+        assert_eq!(Some(11), map.remove_key("stone"));
+        assert_eq!(None, map.handle("textures/stone.png"));
+        assert_eq!(Err(AliasError::Handle(HandleError::Stale)), map.add_alias(texture, "x"));
+
+        // Inserting under an alias only takes the alias.
+        map.add_alias(other, "alias").unwrap();
+        map.add_alias(other, "kept").unwrap();
+        let new = map.insert("alias", 3);
+        assert_eq!(2, map[other]);
+        assert_eq!(Some(other), map.handle("other"));
+        assert_eq!(&["kept".to_string()], map.aliases(other));
+        assert_eq!(2, map.len());
+        assert_eq!(Some(new), map.handle("alias"));
+
+        // Inserting under a key removes its element.
+        map.insert("other", 4);
+        assert!(!map.contains(other));
+        assert_eq!(None, map.handle("kept"));
+        assert_eq!(2, map.len());
+    }
+
+    #[test]
+    fn rename() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let stone = map.insert("stone.png", 1);
+        let rock = map.insert("rock.png", 2);
+        map.add_alias(stone, "stone").unwrap();
+
+        assert_eq!(Ok(stone), map.rename("stone.png", "granite.png"));
+        assert_eq!(Ok(stone), map.rename("stone", "granite"));
+        assert_eq!(Some(&"granite.png".to_string()), map.key_of(stone));
+        assert_eq!(&["granite".to_string()], map.aliases(stone));
+        assert_eq!(None, map.handle("stone.png"));
+        assert_eq!(1, map[stone]);
+
+        assert_eq!(Err(RenameError::NotFound), map.rename("stone.png", "x"));
+        assert_eq!(Err(RenameError::Taken), map.rename("granite.png", "rock.png"));
+        assert_eq!(Ok(stone), map.rename("granite", "granite.png"));
+        assert!(map.aliases(stone).is_empty());
+
+        assert_eq!(Ok(stone), map.force_rename("granite.png", "rock.png"));
+        assert!(!map.contains(rock));
+        assert_eq!(Some(stone), map.handle("rock.png"));
+        assert_eq!(1, map.len());
+
+        let pebble = map.insert("pebble.png", 3);
+        map.add_alias(pebble, "small").unwrap();
+        assert_eq!(Ok(stone), map.force_rename("rock.png", "small"));
+        assert_eq!(Some(stone), map.handle("small"));
+        assert!(map.aliases(pebble).is_empty());
+        assert_eq!(Some(pebble), map.handle("pebble.png"));
+        assert_eq!(2, map.len());
+    }
+
+    #[test]
+    fn non_string_keys() {
+        let mut map: HandleMap<_, u64> = HandleMap::new();
+
+        let handle = map.insert(42u64, "answer");
+
+        assert_eq!(Some(handle), map.handle(&42));
+        assert_eq!(None, map.handle(&7));
+        assert_eq!(Some("answer"), map.remove_key(&42));
+    }
+
+    #[test]
+    fn try_replace_errors() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let mut other: HandleMap<_> = HandleMap::new();
+
+        let one_handle = map.insert("one", 1);
+        let first_handle = other.insert("first", 0);
+        let second_handle = other.insert("second", 0);
+
+        other.replace(first_handle, 1);
+        let first_handle = other.handle("first").unwrap();
+
+        assert_eq!(Err(HandleError::WrongMap), map.try_replace(first_handle, 2));
+        assert_eq!(Ok(1), map.try_replace(one_handle, 11));
+        assert_eq!(Err(HandleError::Stale), map.try_replace(one_handle, 12));
+
+        if cfg!(feature = "map-id") {
+            assert_eq!(Err(HandleError::WrongMap), map.try_replace(second_handle, 3));
+        } else {
+            assert_eq!(Err(HandleError::OutOfBounds), map.try_replace(second_handle, 3));
+        }
     }
 }
@@ -0,0 +1,259 @@
+//! Loading elements on their first lookup.
+
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use fnv::FnvHashMap;
+
+#[cfg(feature = "hot-reload")]
+use hot_reload::Watch;
+use {Generation, Handle, HandleMap};
+
+/// Creates the element for a key, e.g. by reading a file.
+///
+/// Implemented for closures taking the key.
+pub trait Loader<V, K = String> {
+    type Error;
+
+    fn load(&mut self, key: &K) -> Result<V, Self::Error>;
+}
+
+impl<V, K, E, F> Loader<V, K> for F
+where
+    F: FnMut(&K) -> Result<V, E>,
+{
+    type Error = E;
+
+    fn load(&mut self, key: &K) -> Result<V, E> {
+        self(key)
+    }
+}
+
+/// The reason `LoadingHandleMap::get_or_load` failed.
+#[derive(Debug)]
+pub enum LoadError<E> {
+    /// The loader failed.
+    Failed(Arc<E>),
+    /// The loader failed for the same key before
+    /// and was not called again.
+    Cached(Arc<E>),
+}
+
+impl<E> LoadError<E> {
+    /// Returns the error of the loader.
+    pub fn error(&self) -> &E {
+        match *self {
+            LoadError::Failed(ref e) | LoadError::Cached(ref e) => e,
+        }
+    }
+}
+
+impl<E> Clone for LoadError<E> {
+    fn clone(&self) -> Self {
+        match *self {
+            LoadError::Failed(ref e) => LoadError::Failed(e.clone()),
+            LoadError::Cached(ref e) => LoadError::Cached(e.clone()),
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for LoadError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Failed(ref e) => write!(f, "loading failed: {}", e),
+            LoadError::Cached(ref e) => write!(f, "loading failed before: {}", e),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for LoadError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error())
+    }
+}
+
+/// A `HandleMap` with a `Loader` that is called for keys
+/// which are looked up with `get_or_load` but not in the map yet.
+///
+/// Dereferences to the underlying map.
+///
+/// With the `hot-reload` feature, elements can be reloaded
+/// when their files change, see `watch`.
+pub struct LoadingHandleMap<V, L, K = String, G = u16>
+where
+    L: Loader<V, K>,
+{
+    pub(crate) map: HandleMap<V, K, G>,
+    pub(crate) loader: L,
+    failures: FnvHashMap<K, Arc<L::Error>>,
+    #[cfg(feature = "hot-reload")]
+    pub(crate) watch: Option<Watch<V, K, L::Error, G>>,
+}
+
+impl<V, L, K, G> LoadingHandleMap<V, L, K, G>
+where
+    L: Loader<V, K>,
+    K: Hash + Eq + Clone,
+    G: Generation,
+{
+    pub fn new(loader: L) -> Self {
+        LoadingHandleMap::with_map(HandleMap::new(), loader)
+    }
+
+    /// Attaches `loader` to an existing map.
+    pub fn with_map(map: HandleMap<V, K, G>, loader: L) -> Self {
+        LoadingHandleMap {
+            map,
+            loader,
+            failures: FnvHashMap::default(),
+            #[cfg(feature = "hot-reload")]
+            watch: None,
+        }
+    }
+
+    /// Returns the handle stored under `key`, loading
+    /// and inserting the element if there is none.
+    ///
+    /// If loading fails, the error is remembered and returned
+    /// for every later lookup of `key`, without calling the
+    /// loader again, until `forget_failure` is called.
+    pub fn get_or_load<Q>(&mut self, key: &Q) -> Result<Handle<V, G>, LoadError<L::Error>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        if let Some(handle) = self.map.handle(key) {
+            return Ok(handle);
+        }
+        if let Some(e) = self.failures.get(key) {
+            return Err(LoadError::Cached(e.clone()));
+        }
+
+        let key = key.to_owned();
+        match self.loader.load(&key) {
+            Ok(value) => {
+                let handle = self.map.insert(key, value);
+
+                #[cfg(feature = "hot-reload")]
+                {
+                    if let Some(ref mut watch) = self.watch {
+                        let key = self.map.key_of(handle).expect("Bug: loaded element without key");
+                        watch.loaded(handle, key);
+                    }
+                }
+
+                Ok(handle)
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                self.failures.insert(key, e.clone());
+
+                Err(LoadError::Failed(e))
+            }
+        }
+    }
+
+    /// Returns the remembered error of loading `key`.
+    pub fn failure<Q>(&self, key: &Q) -> Option<&L::Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.failures.get(key).map(|e| &**e)
+    }
+
+    /// Forgets that loading `key` failed, so that
+    /// the next `get_or_load` tries again.
+    pub fn forget_failure<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.failures.remove(key).is_some()
+    }
+
+    /// Forgets all failures, see `forget_failure`.
+    pub fn clear_failures(&mut self) {
+        self.failures.clear();
+    }
+
+    pub fn loader(&self) -> &L {
+        &self.loader
+    }
+
+    pub fn loader_mut(&mut self) -> &mut L {
+        &mut self.loader
+    }
+
+    /// Detaches the loader, returning the map and the loader.
+    pub fn into_parts(self) -> (HandleMap<V, K, G>, L) {
+        (self.map, self.loader)
+    }
+}
+
+impl<V, L, K, G> Deref for LoadingHandleMap<V, L, K, G>
+where
+    L: Loader<V, K>,
+{
+    type Target = HandleMap<V, K, G>;
+
+    fn deref(&self) -> &HandleMap<V, K, G> {
+        &self.map
+    }
+}
+
+impl<V, L, K, G> DerefMut for LoadingHandleMap<V, L, K, G>
+where
+    L: Loader<V, K>,
+{
+    fn deref_mut(&mut self) -> &mut HandleMap<V, K, G> {
+        &mut self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use LoadError;
+    use LoadingHandleMap;
+
+    #[test]
+    fn loads_once() {
+        let mut loads = Vec::new();
+        let mut map: LoadingHandleMap<usize, _> = LoadingHandleMap::new(|key: &String| {
+            loads.push(key.clone());
+
+            if key.ends_with(".png") {
+                Ok(key.len())
+            } else {
+                Err(format!("unsupported file {}", key))
+            }
+        });
+
+        let hero = map.get_or_load("hero.png").unwrap();
+        assert_eq!(Ok(hero), map.get_or_load("hero.png").map_err(|e| e.error().clone()));
+        assert_eq!(8, map[hero]);
+
+        match map.get_or_load("hero.obj") {
+            Err(LoadError::Failed(ref e)) if **e == "unsupported file hero.obj" => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match map.get_or_load("hero.obj") {
+            Err(LoadError::Cached(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(map.failure("hero.obj").is_some());
+
+        assert!(map.forget_failure("hero.obj"));
+        assert!(map.get_or_load("hero.obj").is_err());
+
+        map.remove(hero);
+        map.get_or_load("hero.png").unwrap();
+
+        drop(map);
+        assert_eq!(vec!["hero.png", "hero.obj", "hero.obj", "hero.png"], loads);
+    }
+}
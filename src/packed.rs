@@ -0,0 +1,147 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::num::NonZeroU64;
+
+use Handle;
+
+/// A `Handle<T, u32>` packed into 64 bits.
+///
+/// The lower 32 bits are the index, the upper 32 bits the generation.
+/// Generations are never zero, so `Option<PackedHandle<T>>`
+/// is as big as `PackedHandle<T>`.
+///
+/// Note that a packed handle does not remember its map,
+/// even with the `map-id` feature; `HandleMap::unpack` attaches
+/// the map it is called on.
+pub struct PackedHandle<T = ()> {
+    bits: NonZeroU64,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> PackedHandle<T> {
+    /// Returns the raw representation of this handle.
+    #[inline]
+    pub fn to_bits(self) -> u64 {
+        self.bits.get()
+    }
+
+    /// Creates a handle from the result of `to_bits`.
+    ///
+    /// Returns `None` if the generation part is zero,
+    /// which no map ever hands out.
+    #[inline]
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        if bits >> 32 == 0 {
+            return None;
+        }
+
+        NonZeroU64::new(bits).map(|bits| PackedHandle {
+            bits,
+            marker: PhantomData,
+        })
+    }
+
+    #[inline]
+    pub fn index(self) -> usize {
+        self.bits.get() as u32 as usize
+    }
+
+    #[inline]
+    pub(crate) fn generation(self) -> u32 {
+        (self.bits.get() >> 32) as u32
+    }
+}
+
+impl<T> Handle<T, u32> {
+    /// Packs this handle into 64 bits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index does not fit into 32 bits.
+    pub fn pack(self) -> PackedHandle<T> {
+        assert!(self.index <= u32::MAX as usize, "Handle index too big to be packed");
+
+        let bits = (u64::from(self.generation) << 32) | self.index as u64;
+
+        PackedHandle {
+            bits: NonZeroU64::new(bits).expect("Bug: zero generation"),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for PackedHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PackedHandle<T> {}
+
+impl<T> fmt::Debug for PackedHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PackedHandle")
+            .field("index", &self.index())
+            .field("generation", &self.generation())
+            .finish()
+    }
+}
+
+impl<T> PartialEq for PackedHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits
+    }
+}
+
+impl<T> Eq for PackedHandle<T> {}
+
+impl<T> PartialOrd for PackedHandle<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for PackedHandle<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bits.cmp(&other.bits)
+    }
+}
+
+impl<T> Hash for PackedHandle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use {HandleMap, PackedHandle};
+
+    #[test]
+    fn niche() {
+        assert_eq!(8, size_of::<PackedHandle<String>>());
+        assert_eq!(8, size_of::<Option<PackedHandle<String>>>());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut map: HandleMap<u32, String, u32> = HandleMap::new();
+
+        map.insert("zero", 0);
+        let handle = map.insert("one", 1);
+        map.replace(handle, 11);
+        let handle = map.handle("one").unwrap();
+
+        let packed = handle.pack();
+        let bits = packed.to_bits();
+
+        assert_eq!(Some(packed), PackedHandle::from_bits(bits));
+        assert_eq!(handle, map.unpack(packed));
+        assert_eq!(11, map[map.unpack(packed)]);
+        assert_eq!(None, PackedHandle::<u32>::from_bits(1));
+    }
+}
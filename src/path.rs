@@ -0,0 +1,516 @@
+//! Prefix and glob queries over path-like keys.
+//!
+//! Keys are split into segments at `/`. In glob patterns, `*` matches
+//! any number of characters and `?` matches one character, both only
+//! within a segment, while a `**` segment matches any number of whole
+//! segments. So `*.png` only matches files at the top level, but
+//! `**/*.png` matches them in every directory.
+
+use std::collections::btree_set::{self, BTreeSet};
+use std::ops::{Bound, Deref, Index, IndexMut};
+
+use {AliasError, Generation, Handle, HandleMap, IterMut, RenameError, ValuesMut};
+
+/// A `HandleMap` with `String` keys that keeps its keys and aliases
+/// sorted, so that everything under a path can be found quickly.
+///
+/// Dereferences to the underlying map for reading.
+pub struct PathHandleMap<V, G = u16> {
+    map: HandleMap<V, String, G>,
+    index: BTreeSet<String>,
+}
+
+impl<V, G> PathHandleMap<V, G>
+where
+    G: Generation,
+{
+    pub fn new() -> Self {
+        PathHandleMap {
+            map: HandleMap::new(),
+            index: BTreeSet::new(),
+        }
+    }
+
+    /// Returns an iterator over all handles whose key or alias
+    /// starts with `prefix`, together with that key, in key order.
+    ///
+    /// An element is returned once for every matching key.
+    pub fn iter_prefix<'a>(&'a self, prefix: &'a str) -> Prefix<'a, V, G> {
+        Prefix {
+            keys: self.index.range::<str, _>((Bound::Included(prefix), Bound::Unbounded)),
+            prefix,
+            map: &self.map,
+        }
+    }
+
+    /// Returns an iterator over all handles whose key or alias
+    /// matches the glob `pattern`, together with that key, in key order.
+    ///
+    /// See the module documentation for the pattern syntax.
+    pub fn glob<'a>(&'a self, pattern: &'a str) -> Glob<'a, V, G> {
+        // Only whole segments before the first wildcard, without the
+        // slash, as `a/**` also matches `a`.
+        let literal = pattern.find(['*', '?']).unwrap_or(pattern.len());
+        let prefix = pattern[..literal].rfind('/').unwrap_or(0);
+
+        Glob {
+            prefix: self.iter_prefix(&pattern[..prefix]),
+            pattern,
+        }
+    }
+
+    /// Returns a mutable reference to an element, see `HandleMap::get_mut`.
+    pub fn get_mut(&mut self, handle: Handle<V, G>) -> Option<&mut V> {
+        self.map.get_mut(handle)
+    }
+
+    /// See `HandleMap::iter_mut`.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, String, G> {
+        self.map.iter_mut()
+    }
+
+    /// See `HandleMap::values_mut`.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V> {
+        self.map.values_mut()
+    }
+
+    /// Inserts a value under `key`, see `HandleMap::insert`.
+    pub fn insert<S>(&mut self, key: S, value: V) -> Handle<V, G>
+    where
+        S: Into<String>,
+    {
+        let key = key.into();
+
+        if let Some(old) = self.map.handle(&key) {
+            if self.map.key_of(old) == Some(&key) {
+                self.unindex(old.index);
+            }
+        }
+        self.index.insert(key.clone());
+
+        self.map.insert(key, value)
+    }
+
+    /// See `HandleMap::insert_anonymous`.
+    pub fn insert_anonymous(&mut self, value: V) -> Handle<V, G> {
+        self.map.insert_anonymous(value)
+    }
+
+    /// See `HandleMap::add_alias`.
+    pub fn add_alias<S>(&mut self, handle: Handle<V, G>, key: S) -> Result<(), AliasError>
+    where
+        S: Into<String>,
+    {
+        let key = key.into();
+
+        self.map.add_alias(handle, key.clone())?;
+        self.index.insert(key);
+
+        Ok(())
+    }
+
+    /// See `HandleMap::remove_alias`.
+    pub fn remove_alias(&mut self, key: &str) -> Option<Handle<V, G>> {
+        let handle = self.map.remove_alias(key)?;
+        self.index.remove(key);
+
+        Some(handle)
+    }
+
+    /// See `HandleMap::rename`.
+    pub fn rename<S>(&mut self, old: &str, new: S) -> Result<Handle<V, G>, RenameError>
+    where
+        S: Into<String>,
+    {
+        let new = new.into();
+
+        let handle = self.map.rename(old, new.clone())?;
+        self.index.remove(old);
+        self.index.insert(new);
+
+        Ok(handle)
+    }
+
+    /// See `HandleMap::force_rename`.
+    pub fn force_rename<S>(&mut self, old: &str, new: S) -> Result<Handle<V, G>, RenameError>
+    where
+        S: Into<String>,
+    {
+        let new = new.into();
+        let handle = self.map.handle(old).ok_or(RenameError::NotFound)?;
+
+        match self.map.handle(&new) {
+            Some(owner) if owner != handle && self.map.key_of(owner) == Some(&new) => self.unindex(owner.index),
+            _ => {}
+        }
+        self.map.force_rename(old, new.clone())?;
+        self.index.remove(old);
+        self.index.insert(new);
+
+        Ok(handle)
+    }
+
+    /// Renames every key and alias starting with `old` to start
+    /// with `new` instead, keeping all handles valid.
+    ///
+    /// Returns the number of renamed keys. Fails without renaming
+    /// anything if one of the new keys belongs to an element that
+    /// is not renamed.
+    pub fn rename_prefix(&mut self, old: &str, new: &str) -> Result<usize, RenameError> {
+        let renamed: Vec<(String, String)> = self
+            .iter_prefix(old)
+            .map(|(_, key)| (key.to_string(), format!("{}{}", new, &key[old.len()..])))
+            .collect();
+
+        for (_, to) in &renamed {
+            if !to.starts_with(old) && self.map.keys_to_indices.contains_key(to) {
+                return Err(RenameError::Taken);
+            }
+        }
+
+        // Unbind all old keys first, a new key might be another old key.
+        let mut handles = Vec::with_capacity(renamed.len());
+        for (from, _) in &renamed {
+            handles.push(self.map.keys_to_indices.remove(from).expect("Bug: indexed key not in map"));
+            self.index.remove(from);
+        }
+        for ((_, to), &handle) in renamed.iter().zip(&handles) {
+            self.map.keys_to_indices.insert(to.clone(), handle);
+            self.index.insert(to.clone());
+        }
+
+        let rename = |key: &mut String| {
+            if key.starts_with(old) {
+                *key = format!("{}{}", new, &key[old.len()..]);
+            }
+        };
+        handles.sort();
+        handles.dedup();
+        for handle in handles {
+            self.map.keys[handle.index].iter_mut().for_each(rename);
+            self.map.aliases[handle.index].iter_mut().for_each(rename);
+        }
+
+        Ok(renamed.len())
+    }
+
+    /// See `HandleMap::replace`.
+    pub fn replace(&mut self, handle: Handle<V, G>, value: V) -> V {
+        self.map.replace(handle, value)
+    }
+
+    /// See `HandleMap::pop`.
+    pub fn pop(&mut self) -> Option<V> {
+        let index = self.map.last_index()?;
+        self.unindex(index);
+
+        self.map.pop()
+    }
+
+    /// See `HandleMap::remove`.
+    pub fn remove(&mut self, handle: Handle<V, G>) -> Option<V> {
+        if self.map.contains(handle) {
+            self.unindex(handle.index);
+        }
+
+        self.map.remove(handle)
+    }
+
+    /// See `HandleMap::remove_key`.
+    pub fn remove_key(&mut self, key: &str) -> Option<V> {
+        let handle = self.map.handle(key)?;
+
+        self.remove(handle)
+    }
+
+    /// Removes every element that has a key or alias
+    /// starting with `prefix`, returning them in key order.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Vec<V> {
+        let handles: Vec<_> = self.iter_prefix(prefix).map(|(handle, _)| handle).collect();
+
+        // Elements with several matching keys are only removed once.
+        handles.into_iter().filter_map(|handle| self.remove(handle)).collect()
+    }
+
+    pub fn into_inner(self) -> HandleMap<V, String, G> {
+        self.map
+    }
+
+    /// Removes the key and aliases of the slot at `index` from the index.
+    fn unindex(&mut self, index: usize) {
+        for key in self.map.keys[index].iter().chain(&self.map.aliases[index]) {
+            self.index.remove(key);
+        }
+    }
+}
+
+impl<V, G> Default for PathHandleMap<V, G>
+where
+    G: Generation,
+{
+    fn default() -> Self {
+        PathHandleMap::new()
+    }
+}
+
+impl<V, G> From<HandleMap<V, String, G>> for PathHandleMap<V, G>
+where
+    G: Generation,
+{
+    fn from(map: HandleMap<V, String, G>) -> Self {
+        PathHandleMap {
+            index: map.keys().cloned().collect(),
+            map,
+        }
+    }
+}
+
+impl<V, G> Deref for PathHandleMap<V, G> {
+    type Target = HandleMap<V, String, G>;
+
+    fn deref(&self) -> &HandleMap<V, String, G> {
+        &self.map
+    }
+}
+
+impl<V, G> Index<Handle<V, G>> for PathHandleMap<V, G>
+where
+    G: Generation,
+{
+    type Output = V;
+
+    fn index(&self, index: Handle<V, G>) -> &V {
+        &self.map[index]
+    }
+}
+
+impl<V, G> IndexMut<Handle<V, G>> for PathHandleMap<V, G>
+where
+    G: Generation,
+{
+    fn index_mut(&mut self, index: Handle<V, G>) -> &mut V {
+        &mut self.map[index]
+    }
+}
+
+/// An iterator over the handles and keys under a prefix,
+/// returned by `PathHandleMap::iter_prefix`.
+pub struct Prefix<'a, V: 'a, G: 'a = u16> {
+    keys: btree_set::Range<'a, String>,
+    prefix: &'a str,
+    map: &'a HandleMap<V, String, G>,
+}
+
+impl<'a, V, G> Iterator for Prefix<'a, V, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<V, G>, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.keys.next().filter(|key| key.starts_with(self.prefix))?;
+        let handle = self.map.handle(key).expect("Bug: indexed key not in map");
+
+        Some((handle, key))
+    }
+}
+
+/// An iterator over the handles and keys matching a glob pattern,
+/// returned by `PathHandleMap::glob`.
+pub struct Glob<'a, V: 'a, G: 'a = u16> {
+    prefix: Prefix<'a, V, G>,
+    pattern: &'a str,
+}
+
+impl<'a, V, G> Iterator for Glob<'a, V, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<V, G>, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pattern = self.pattern;
+
+        self.prefix.find(|&(_, key)| glob_matches(pattern, key))
+    }
+}
+
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<_> = pattern.split('/').collect();
+    let path: Vec<_> = path.split('/').collect();
+
+    segments_match(&pattern, &path)
+}
+
+fn segments_match(pattern: &[&str], path: &[&str]) -> bool {
+    wildcard_match(pattern, path, |&segment| segment == "**", |segment, name| {
+        segment_matches(segment, name)
+    })
+}
+
+fn segment_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let name: Vec<_> = name.chars().collect();
+
+    wildcard_match(&pattern, &name, |&c| c == '*', |&p, &c| p == '?' || p == c)
+}
+
+/// Matches `text` against `pattern`, in which every `is_star` element
+/// matches any number of elements of `text` and every other element
+/// matches a single one.
+///
+/// Only the most recent star is ever backtracked to, which is enough
+/// because an earlier star can't match anything a later one can't,
+/// so this takes `O(pattern.len() * text.len())` steps.
+fn wildcard_match<P, T, S, M>(pattern: &[P], text: &[T], is_star: S, matches: M) -> bool
+where
+    S: Fn(&P) -> bool,
+    M: Fn(&P, &T) -> bool,
+{
+    let (mut p, mut t) = (0, 0);
+    // The pattern position after the last star,
+    // and the text position it is tried to match up to.
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && is_star(&pattern[p]) {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if p < pattern.len() && matches(&pattern[p], &text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((after_star, matched)) = backtrack {
+            // Let the star match one more element.
+            p = after_star;
+            t = matched + 1;
+            backtrack = Some((after_star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(is_star)
+}
+
+#[cfg(test)]
+mod tests {
+    use {Handle, PathHandleMap, RenameError};
+
+    fn sample() -> PathHandleMap<u32> {
+        let mut map = PathHandleMap::new();
+
+        map.insert("textures/characters/hero.png", 1);
+        map.insert("textures/characters/villain.png", 2);
+        map.insert("textures/sky.png", 3);
+        map.insert("sounds/hero.ogg", 4);
+        map.insert("logo.png", 5);
+
+        map
+    }
+
+    fn keys<'a, I: Iterator<Item = (Handle<u32>, &'a str)>>(iter: I) -> Vec<&'a str> {
+        iter.map(|(_, key)| key).collect()
+    }
+
+    #[test]
+    fn prefix() {
+        let mut map = sample();
+        let hero = map.handle("textures/characters/hero.png").unwrap();
+        map.add_alias(hero, "characters/hero").unwrap();
+
+        assert_eq!(
+            vec!["textures/characters/hero.png", "textures/characters/villain.png"],
+            keys(map.iter_prefix("textures/characters/"))
+        );
+        assert_eq!(vec!["characters/hero"], keys(map.iter_prefix("char")));
+
+        assert_eq!(vec![3], map.remove_prefix("textures/s"));
+        assert_eq!(vec![1, 2], map.remove_prefix("textures/"));
+        assert_eq!(0, map.iter_prefix("char").count());
+        assert_eq!(2, map.len());
+    }
+
+    #[test]
+    fn rename_prefix() {
+        let mut map = sample();
+        let hero = map.handle("textures/characters/hero.png").unwrap();
+
+        assert_eq!(Err(RenameError::Taken), map.rename_prefix("textures/sky", "logo"));
+        assert_eq!(Ok(3), map.rename_prefix("textures/", "textures/old/"));
+
+        assert_eq!(Some(hero), map.handle("textures/old/characters/hero.png"));
+        assert_eq!(Some(&"textures/old/characters/hero.png".to_string()), map.key_of(hero));
+        assert_eq!(1, map[hero]);
+        assert_eq!(3, map.iter_prefix("textures/old/").count());
+        assert_eq!(3, map.iter_prefix("textures/").count());
+    }
+
+    #[test]
+    fn insert_takes_alias() {
+        let mut map = sample();
+        let hero = map.handle("textures/characters/hero.png").unwrap();
+        map.add_alias(hero, "aliases/hero").unwrap();
+
+        let new = map.insert("aliases/hero", 6);
+
+        assert_eq!(1, map[hero]);
+        assert_eq!(2, map.iter_prefix("textures/characters/").count());
+        assert_eq!(vec![(new, "aliases/hero")], map.iter_prefix("aliases/").collect::<Vec<_>>());
+        assert_eq!(Ok(hero), map.force_rename("textures/characters/hero.png", "aliases/hero"));
+        assert!(!map.contains(new));
+        assert_eq!(1, map.iter_prefix("textures/characters/").count());
+    }
+
+    #[test]
+    fn glob() {
+        let map = sample();
+
+        assert_eq!(vec!["logo.png"], keys(map.glob("*.png")));
+        assert_eq!(
+            vec!["sounds/hero.ogg", "textures/characters/hero.png"],
+            keys(map.glob("**/hero*"))
+        );
+        assert_eq!(vec!["textures/sky.png"], keys(map.glob("textures/s?y.*")));
+        assert_eq!(4, map.glob("**/*.png").count());
+        assert_eq!(0, map.glob("textures/*.ogg").count());
+    }
+
+    #[test]
+    fn glob_backtracks_linearly() {
+        let mut map: PathHandleMap<u32> = PathHandleMap::new();
+
+        let name = "a".repeat(40);
+        let deep = vec!["a"; 40].join("/");
+        map.insert(name.clone(), 1);
+        map.insert(deep.clone(), 2);
+
+        // Both would take exponential time if every star
+        // retried the rest of the pattern at every offset.
+        assert_eq!(0, map.glob("*a*a*a*a*a*a*a*a*b").count());
+        assert_eq!(0, map.glob("**/a/**/a/**/a/**/a/**/a/**/a/**/b").count());
+        assert_eq!(vec![name.as_str()], keys(map.glob("*a*a*a*a*a*a*a*a*a")));
+        assert_eq!(vec![deep.as_str()], keys(map.glob("**/a/**/a/**/a/**/a/**/a/**/a/**")));
+
+        assert!(super::segment_matches("a*", "a"));
+        assert!(super::segment_matches("*", ""));
+        assert!(!super::segment_matches("?", ""));
+        assert!(super::segment_matches("*?b*", "aabab"));
+        assert!(super::glob_matches("a/**", "a"));
+        assert!(!super::glob_matches("a/**/b", "a/c/bb"));
+    }
+
+    #[test]
+    fn glob_agrees_with_matcher() {
+        let mut map: PathHandleMap<u32> = PathHandleMap::new();
+
+        map.insert("a", 1);
+        map.insert("a/b", 2);
+        map.insert("ab", 3);
+        map.insert("ab/c", 4);
+
+        assert_eq!(vec!["a", "a/b"], keys(map.glob("a/**")));
+        assert_eq!(vec!["a/b"], keys(map.glob("a/*")));
+        assert_eq!(vec!["ab/c"], keys(map.glob("a?/c")));
+        assert_eq!(vec!["a/b"], keys(map.glob("a/b")));
+    }
+}
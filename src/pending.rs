@@ -0,0 +1,269 @@
+//! Elements that are still being loaded.
+//!
+//! `HandleMap::reserve` inserts a placeholder and marks its slot as
+//! pending, so a handle can be handed out right away. Whoever loads
+//! the element then calls `fulfill` or `fail`, either directly or from
+//! another thread or task through a `Completer`, whose messages are
+//! applied by `HandleMap::complete`.
+//!
+//! Loading states are not saved in snapshots,
+//! pending elements are saved with their placeholder.
+
+use std::error::Error;
+use std::hash::Hash;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use {Generation, Handle, HandleMap};
+
+/// The loading state of an element, see `HandleMap::state`.
+#[derive(Clone, Debug)]
+pub enum LoadState {
+    /// The element is still being loaded, the slot holds a placeholder.
+    Pending,
+    /// The element was loaded, or was never pending.
+    Ready,
+    /// Loading failed, the slot still holds the placeholder.
+    Failed(Arc<dyn Error + Send + Sync>),
+}
+
+impl LoadState {
+    pub fn is_pending(&self) -> bool {
+        matches!(*self, LoadState::Pending)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        matches!(*self, LoadState::Ready)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(*self, LoadState::Failed(_))
+    }
+}
+
+impl<V, K, G> HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    /// Inserts `V::default()` as a placeholder under `key`
+    /// and marks it as pending.
+    ///
+    /// See `reserve_with`.
+    pub fn reserve<S>(&mut self, key: S) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+        V: Default,
+    {
+        self.reserve_with(key, V::default())
+    }
+
+    /// Inserts `placeholder` under `key` and marks it as pending,
+    /// until the element is passed to `fulfill`.
+    ///
+    /// The placeholder is returned by all accessors in the meantime.
+    pub fn reserve_with<S>(&mut self, key: S, placeholder: V) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let handle = self.insert(key, placeholder);
+        self.pending.insert(handle.index, LoadState::Pending);
+
+        handle
+    }
+
+    /// Returns the loading state of the element `handle` points to,
+    /// or `None` if the handle is dead.
+    pub fn state(&self, handle: Handle<V, G>) -> Option<LoadState> {
+        self.check(handle).ok()?;
+
+        Some(self.pending.get(&handle.index).cloned().unwrap_or(LoadState::Ready))
+    }
+
+    /// Replaces the placeholder of a pending or failed element
+    /// with `value` and marks it as ready. Handles stay valid.
+    ///
+    /// Returns the placeholder, or `None` if the handle is dead or
+    /// the element is already ready, in which case `value` is dropped.
+    pub fn fulfill(&mut self, handle: Handle<V, G>, value: V) -> Option<V> {
+        self.check(handle).ok()?;
+        self.pending.remove(&handle.index)?;
+
+        self.storage[handle.index].replace(value)
+    }
+
+    /// Marks a pending element as failed, keeping its placeholder.
+    ///
+    /// Returns `false` if the handle is dead or the element is not pending.
+    pub fn fail<E>(&mut self, handle: Handle<V, G>, error: E) -> bool
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.fail_with(handle, Arc::from(error.into()))
+    }
+
+    /// Applies everything sent through the `Completer`s of `completions`
+    /// so far, see `fulfill` and `fail`. Completions for dead handles
+    /// are ignored.
+    ///
+    /// Returns the number of completions that were applied.
+    pub fn complete(&mut self, completions: &Completions<V, G>) -> usize {
+        let mut applied = 0;
+
+        for completion in completions.receiver.try_iter() {
+            let done = match completion {
+                Completion::Fulfill(handle, value) => self.fulfill(handle, value).is_some(),
+                Completion::Fail(handle, error) => self.fail_with(handle, error),
+            };
+            if done {
+                applied += 1;
+            }
+        }
+
+        applied
+    }
+
+    fn fail_with(&mut self, handle: Handle<V, G>, error: Arc<dyn Error + Send + Sync>) -> bool {
+        if self.check(handle).is_err() {
+            return false;
+        }
+
+        match self.pending.get_mut(&handle.index) {
+            Some(state) if state.is_pending() => {
+                *state = LoadState::Failed(error);
+
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Creates a `Completer` for completing pending elements from
+/// other threads or tasks, and the `Completions` to apply with
+/// `HandleMap::complete`.
+///
+/// Works with any executor, as sending never blocks.
+pub fn completion_channel<V, G>() -> (Completer<V, G>, Completions<V, G>) {
+    let (sender, receiver) = mpsc::channel();
+
+    (Completer { sender }, Completions { receiver })
+}
+
+/// Sends loaded elements to a `HandleMap`.
+pub struct Completer<V, G = u16> {
+    sender: Sender<Completion<V, G>>,
+}
+
+impl<V, G> Completer<V, G> {
+    /// Sends `value` for the pending element `handle` points to.
+    ///
+    /// Returns `false` if the `Completions` were dropped.
+    pub fn fulfill(&self, handle: Handle<V, G>, value: V) -> bool {
+        self.sender.send(Completion::Fulfill(handle, value)).is_ok()
+    }
+
+    /// Sends a failure for the pending element `handle` points to.
+    ///
+    /// Returns `false` if the `Completions` were dropped.
+    pub fn fail<E>(&self, handle: Handle<V, G>, error: E) -> bool
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.sender.send(Completion::Fail(handle, Arc::from(error.into()))).is_ok()
+    }
+}
+
+impl<V, G> Clone for Completer<V, G> {
+    fn clone(&self) -> Self {
+        Completer {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// The receiving end of `completion_channel`.
+pub struct Completions<V, G = u16> {
+    receiver: Receiver<Completion<V, G>>,
+}
+
+enum Completion<V, G> {
+    Fulfill(Handle<V, G>, V),
+    Fail(Handle<V, G>, Arc<dyn Error + Send + Sync>),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use {completion_channel, HandleMap, LoadState};
+
+    #[test]
+    fn fulfill_keeps_handles() {
+        let mut map: HandleMap<&str> = HandleMap::new();
+
+        let hero = map.reserve_with("hero", "missing");
+        assert!(map.state(hero).unwrap().is_pending());
+        assert_eq!("missing", map[hero]);
+
+        assert!(map.fail(hero, "file not found"));
+        assert!(!map.fail(hero, "again"));
+        match map.state(hero) {
+            Some(LoadState::Failed(ref e)) => assert_eq!("file not found", e.to_string()),
+            state => panic!("unexpected state {:?}", state),
+        }
+
+        assert_eq!(Some("missing"), map.fulfill(hero, "hero"));
+        assert_eq!(None, map.fulfill(hero, "other"));
+        assert!(map.state(hero).unwrap().is_ready());
+        assert_eq!("hero", map[hero]);
+
+        let other = map.reserve("other");
+        assert_eq!("", map[other]);
+        map.remove(other);
+        assert!(map.state(other).is_none());
+        let reused = map.insert("reused", "reused");
+        assert!(map.state(reused).unwrap().is_ready());
+    }
+
+    #[test]
+    fn complete_from_threads() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+        let (completer, completions) = completion_channel();
+
+        let handles: Vec<_> = (0..4).map(|i| map.reserve(format!("asset{}", i))).collect();
+        let dead = map.reserve("dead");
+        map.remove(dead);
+
+        let threads: Vec<_> = handles
+            .iter()
+            .map(|&handle| {
+                let completer = completer.clone();
+
+                thread::spawn(move || {
+                    if handle.index() == 3 {
+                        completer.fail(handle, "corrupt")
+                    } else {
+                        completer.fulfill(handle, handle.index() as u32 + 10)
+                    }
+                })
+            })
+            .collect();
+        assert!(completer.fulfill(dead, 1));
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+
+        assert_eq!(4, map.complete(&completions));
+        assert_eq!(0, map.complete(&completions));
+        assert_eq!(10, map[handles[0]]);
+        assert_eq!(12, map[handles[2]]);
+        assert!(map.state(handles[3]).unwrap().is_failed());
+        assert_eq!(0, map[handles[3]]);
+
+        drop(completions);
+        assert!(!completer.fulfill(handles[3], 13));
+    }
+}
@@ -0,0 +1,550 @@
+//! Maps that attach extra data to the elements of a `HandleMap`.
+//!
+//! Both maps store the generation of the handle next to every value.
+//! A value is only returned for a handle of exactly that generation,
+//! so once the slot of the primary map is reused, the old value is
+//! ignored until it is overwritten or evicted. A stale handle does not
+//! overwrite the value of a newer generation.
+//!
+//! With `OverflowPolicy::Wrap`, generations do not tell which handle is
+//! newer, so the maps need to know the policy of the primary map,
+//! see `set_overflow_policy`.
+
+use std::collections::hash_map;
+use std::hash::Hash;
+use std::iter::Enumerate;
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+use fnv::FnvHashMap;
+
+use handle::MapId;
+use {Generation, Handle, HandleError, HandleMap, OverflowPolicy};
+
+/// A secondary map that stores its values in a `Vec`,
+/// indexed like the slots of the primary map.
+///
+/// Use it for data most elements have.
+/// See `SparseSecondaryHandleMap` for data only a few elements have.
+pub struct SecondaryHandleMap<T, X, G = u16> {
+    slots: Vec<Option<(G, X)>>,
+    len: usize,
+    map: Option<MapId>,
+    overflow: OverflowPolicy,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T, X, G> SecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    pub fn new() -> Self {
+        SecondaryHandleMap {
+            slots: Vec::new(),
+            len: 0,
+            map: None,
+            overflow: OverflowPolicy::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the overflow policy of the primary map, as far as this map knows.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /// Sets the overflow policy of the primary map. Needs to be
+    /// `OverflowPolicy::Wrap` if the primary map wraps generations,
+    /// otherwise values of wrapped handles are not stored, see `insert`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    /// Returns the number of values, including
+    /// those of stale handles that were not evicted yet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if there is a value for `handle`.
+    pub fn contains(&self, handle: Handle<T, G>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    /// Returns the value for `handle`, or `None` if there is none
+    /// or it was stored for a different generation of the slot.
+    pub fn get(&self, handle: Handle<T, G>) -> Option<&X> {
+        if self.map != Some(handle.map) {
+            return None;
+        }
+
+        match self.slots.get(handle.index) {
+            Some(&Some((generation, ref value))) if generation == handle.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Like `get`, but returns a mutable reference.
+    pub fn get_mut(&mut self, handle: Handle<T, G>) -> Option<&mut X> {
+        if self.map != Some(handle.map) {
+            return None;
+        }
+
+        match self.slots.get_mut(handle.index) {
+            Some(&mut Some((generation, ref mut value))) if generation == handle.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Stores `value` for `handle`, returning the previous value
+    /// for the same handle.
+    ///
+    /// A value stored for an older generation of the slot is evicted.
+    /// If there already is a value for a newer generation, nothing is
+    /// stored and `value` is dropped. With `OverflowPolicy::Wrap`, no
+    /// generation counts as newer, so `value` always replaces the
+    /// value stored for the slot.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `handle` belongs to a different primary map
+    /// than the handles inserted before.
+    pub fn insert(&mut self, handle: Handle<T, G>, value: X) -> Option<X> {
+        assert_map(&mut self.map, handle);
+
+        if handle.index >= self.slots.len() {
+            self.slots.resize_with(handle.index + 1, || None);
+        }
+
+        let slot = &mut self.slots[handle.index];
+        match *slot {
+            Some((generation, _)) if is_newer(self.overflow, generation, handle) => None,
+            Some((generation, _)) => {
+                let old = slot.replace((handle.generation, value));
+
+                old.filter(|_| generation == handle.generation).map(|(_, value)| value)
+            }
+            None => {
+                *slot = Some((handle.generation, value));
+                self.len += 1;
+
+                None
+            }
+        }
+    }
+
+    /// Removes the value for `handle`.
+    pub fn remove(&mut self, handle: Handle<T, G>) -> Option<X> {
+        self.get(handle)?;
+        self.len -= 1;
+
+        self.slots[handle.index].take().map(|(_, value)| value)
+    }
+
+    /// Removes all values for which `f` returns `false`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(Handle<T, G>, &mut X) -> bool,
+    {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let keep = match *slot {
+                Some((generation, ref mut value)) => {
+                    let map = self.map.expect("Bug: value without map id");
+
+                    f(Handle::new(index, generation, map), value)
+                }
+                None => true,
+            };
+
+            if !keep {
+                *slot = None;
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// Removes all values whose handle is no longer alive in `primary`.
+    pub fn evict_stale<V, K>(&mut self, primary: &HandleMap<V, K, G>)
+    where
+        K: Hash + Eq,
+    {
+        self.retain(|handle, _| primary.contains(handle.cast()));
+    }
+
+    /// Returns an iterator over all handles and values, in slot order.
+    ///
+    /// Values of stale handles that were not evicted yet are included.
+    pub fn iter(&self) -> SecondaryIter<'_, T, X, G> {
+        SecondaryIter {
+            slots: self.slots.iter().enumerate(),
+            map: self.map,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T, X, G> Default for SecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    fn default() -> Self {
+        SecondaryHandleMap::new()
+    }
+}
+
+impl<T, X, G> Index<Handle<T, G>> for SecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    type Output = X;
+
+    fn index(&self, handle: Handle<T, G>) -> &X {
+        self.get(handle).unwrap_or_else(|| missing(handle))
+    }
+}
+
+impl<T, X, G> IndexMut<Handle<T, G>> for SecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    fn index_mut(&mut self, handle: Handle<T, G>) -> &mut X {
+        match self.get_mut(handle) {
+            Some(value) => value,
+            None => missing(handle),
+        }
+    }
+}
+
+/// An iterator over the handles and values of a `SecondaryHandleMap`.
+pub struct SecondaryIter<'a, T, X: 'a, G: 'a = u16> {
+    slots: Enumerate<slice::Iter<'a, Option<(G, X)>>>,
+    map: Option<MapId>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, T, X, G> Iterator for SecondaryIter<'a, T, X, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<T, G>, &'a X);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (index, slot) in &mut self.slots {
+            if let Some((generation, ref value)) = *slot {
+                let map = self.map.expect("Bug: value without map id");
+
+                return Some((Handle::new(index, generation, map), value));
+            }
+        }
+
+        None
+    }
+}
+
+/// A secondary map that stores its values in a hash map.
+///
+/// Use it for data only a few elements have.
+/// See `SecondaryHandleMap` for data most elements have.
+pub struct SparseSecondaryHandleMap<T, X, G = u16> {
+    slots: FnvHashMap<usize, (G, X)>,
+    map: Option<MapId>,
+    overflow: OverflowPolicy,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T, X, G> SparseSecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    pub fn new() -> Self {
+        SparseSecondaryHandleMap {
+            slots: FnvHashMap::default(),
+            map: None,
+            overflow: OverflowPolicy::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// See `SecondaryHandleMap::overflow_policy`.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /// See `SecondaryHandleMap::set_overflow_policy`.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    /// Returns the number of values, including
+    /// those of stale handles that were not evicted yet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if there is a value for `handle`.
+    pub fn contains(&self, handle: Handle<T, G>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    /// Returns the value for `handle`, or `None` if there is none
+    /// or it was stored for a different generation of the slot.
+    pub fn get(&self, handle: Handle<T, G>) -> Option<&X> {
+        if self.map != Some(handle.map) {
+            return None;
+        }
+
+        match self.slots.get(&handle.index) {
+            Some(&(generation, ref value)) if generation == handle.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Like `get`, but returns a mutable reference.
+    pub fn get_mut(&mut self, handle: Handle<T, G>) -> Option<&mut X> {
+        if self.map != Some(handle.map) {
+            return None;
+        }
+
+        match self.slots.get_mut(&handle.index) {
+            Some(&mut (generation, ref mut value)) if generation == handle.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Stores `value` for `handle`, see `SecondaryHandleMap::insert`.
+    pub fn insert(&mut self, handle: Handle<T, G>, value: X) -> Option<X> {
+        assert_map(&mut self.map, handle);
+
+        match self.slots.entry(handle.index) {
+            hash_map::Entry::Occupied(mut e) => {
+                let generation = e.get().0;
+                if is_newer(self.overflow, generation, handle) {
+                    return None;
+                }
+
+                let (_, old) = e.insert((handle.generation, value));
+
+                Some(old).filter(|_| generation == handle.generation)
+            }
+            hash_map::Entry::Vacant(e) => {
+                e.insert((handle.generation, value));
+
+                None
+            }
+        }
+    }
+
+    /// Removes the value for `handle`.
+    pub fn remove(&mut self, handle: Handle<T, G>) -> Option<X> {
+        self.get(handle)?;
+
+        self.slots.remove(&handle.index).map(|(_, value)| value)
+    }
+
+    /// Removes all values for which `f` returns `false`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(Handle<T, G>, &mut X) -> bool,
+    {
+        let map = self.map;
+
+        self.slots.retain(|&index, &mut (generation, ref mut value)| {
+            f(Handle::new(index, generation, map.expect("Bug: value without map id")), value)
+        });
+    }
+
+    /// Removes all values whose handle is no longer alive in `primary`.
+    pub fn evict_stale<V, K>(&mut self, primary: &HandleMap<V, K, G>)
+    where
+        K: Hash + Eq,
+    {
+        self.retain(|handle, _| primary.contains(handle.cast()));
+    }
+
+    /// Returns an iterator over all handles and values, in arbitrary order.
+    ///
+    /// Values of stale handles that were not evicted yet are included.
+    pub fn iter(&self) -> SparseSecondaryIter<'_, T, X, G> {
+        SparseSecondaryIter {
+            slots: self.slots.iter(),
+            map: self.map,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T, X, G> Default for SparseSecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    fn default() -> Self {
+        SparseSecondaryHandleMap::new()
+    }
+}
+
+impl<T, X, G> Index<Handle<T, G>> for SparseSecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    type Output = X;
+
+    fn index(&self, handle: Handle<T, G>) -> &X {
+        self.get(handle).unwrap_or_else(|| missing(handle))
+    }
+}
+
+impl<T, X, G> IndexMut<Handle<T, G>> for SparseSecondaryHandleMap<T, X, G>
+where
+    G: Generation,
+{
+    fn index_mut(&mut self, handle: Handle<T, G>) -> &mut X {
+        match self.get_mut(handle) {
+            Some(value) => value,
+            None => missing(handle),
+        }
+    }
+}
+
+/// An iterator over the handles and values of a `SparseSecondaryHandleMap`.
+pub struct SparseSecondaryIter<'a, T, X: 'a, G: 'a = u16> {
+    slots: hash_map::Iter<'a, usize, (G, X)>,
+    map: Option<MapId>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, T, X, G> Iterator for SparseSecondaryIter<'a, T, X, G>
+where
+    G: Generation,
+{
+    type Item = (Handle<T, G>, &'a X);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&index, &(generation, ref value)) = self.slots.next()?;
+        let map = self.map.expect("Bug: value without map id");
+
+        Some((Handle::new(index, generation, map), value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.slots.size_hint()
+    }
+}
+
+/// Remembers the map of the first inserted handle
+/// and panics if `handle` is from a different one.
+fn assert_map<T, G>(map: &mut Option<MapId>, handle: Handle<T, G>) {
+    if *map.get_or_insert(handle.map) != handle.map {
+        panic!("Tried to use invalid handle: {}", HandleError::WrongMap);
+    }
+}
+
+/// Returns `true` if `generation` is known to be newer than the
+/// generation of `handle`, which can't be told if generations wrap.
+fn is_newer<T, G: Generation>(overflow: OverflowPolicy, generation: G, handle: Handle<T, G>) -> bool {
+    overflow != OverflowPolicy::Wrap && generation > handle.generation
+}
+
+fn missing<T, G: Generation>(handle: Handle<T, G>) -> ! {
+    panic!("No value for handle {:?}", handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use {HandleMap, OverflowPolicy, SecondaryHandleMap, SparseSecondaryHandleMap};
+
+    #[test]
+    fn stale_values_are_ignored() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let mut residency = SecondaryHandleMap::new();
+
+        let one = map.insert("one", 1);
+        let two = map.insert("two", 2);
+        residency.insert(one, "gpu");
+        residency.insert(two, "cpu");
+        assert_eq!(Some("gpu"), residency.insert(one, "both"));
+
+        map.remove(one);
+        let three = map.insert("three", 3);
+        assert_eq!(one.index(), three.index());
+        assert_eq!(None, residency.get(three));
+        assert_eq!("both", residency[one]);
+
+        residency.evict_stale(&map);
+        assert!(!residency.contains(one));
+        assert_eq!(1, residency.len());
+
+        assert_eq!(None, residency.insert(three, "gpu"));
+        assert_eq!("gpu", residency[three]);
+        assert_eq!(vec![(three, &"gpu"), (two, &"cpu")], residency.iter().collect::<Vec<_>>());
+
+        // A stale handle does not evict the value of the live one.
+        assert_eq!(None, residency.insert(one, "old"));
+        assert!(!residency.contains(one));
+        assert_eq!("gpu", residency[three]);
+        assert_eq!(2, residency.len());
+    }
+
+    #[test]
+    fn wrapped_generations() {
+        let mut map: HandleMap<u32, String, u8> = HandleMap::new();
+        map.set_overflow_policy(OverflowPolicy::Wrap);
+        let mut dense = SecondaryHandleMap::new();
+        let mut sparse = SparseSecondaryHandleMap::new();
+        dense.set_overflow_policy(OverflowPolicy::Wrap);
+        sparse.set_overflow_policy(OverflowPolicy::Wrap);
+
+        let first = map.insert_anonymous(0);
+        let mut last = first;
+        for i in 1..=u8::MAX as u32 {
+            dense.insert(last, i);
+            sparse.insert(last, i);
+            map.remove(last);
+            last = map.insert_anonymous(i);
+        }
+        assert_eq!(first, last);
+
+        assert_eq!(None, dense.insert(last, 0));
+        assert_eq!(None, sparse.insert(last, 0));
+        assert_eq!(Some(&0), dense.get(last));
+        assert_eq!(Some(&0), sparse.get(last));
+        assert_eq!(1, dense.len());
+        assert_eq!(1, sparse.len());
+    }
+
+    #[test]
+    fn sparse() {
+        let mut map: HandleMap<_> = HandleMap::new();
+        let mut selected = SparseSecondaryHandleMap::new();
+
+        let one = map.insert("one", 1);
+        map.insert("two", 2);
+        selected.insert(one, ());
+
+        map.replace(one, 11);
+        let new_one = map.handle("one").unwrap();
+        assert!(!selected.contains(new_one));
+
+        selected.insert(new_one, ());
+        assert!(!selected.contains(one));
+        assert_eq!(1, selected.len());
+        selected.insert(one, ());
+        assert!(selected.contains(new_one));
+        assert!(!selected.contains(one));
+
+        selected.retain(|h, _| h != new_one);
+        assert!(selected.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+//! Serialization of maps and handles.
+//!
+//! A map is serialized slot by slot, including vacant slots,
+//! generations and the free list, so that deserializing it
+//! restores the exact handles that were valid before.
+//!
+//! The format does not depend on the `map-id` feature: the map id is
+//! always written, as zero without the feature, like in snapshots.
+//! Maps without an id and handles without one, as written by older
+//! versions, are accepted as well.
+
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use handle::{MapId, OwnedMapId};
+use {Generation, Handle, HandleMap, OverflowPolicy};
+
+#[derive(Serialize)]
+struct MapRef<'a, V: 'a, K: 'a, G: 'a> {
+    generations: &'a [G],
+    keys: &'a [Option<K>],
+    aliases: &'a [Vec<K>],
+    storage: &'a [Option<V>],
+    free: &'a [usize],
+    overflow: OverflowPolicy,
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct MapData<V, K, G> {
+    generations: Vec<G>,
+    keys: Vec<Option<K>>,
+    aliases: Vec<Vec<K>>,
+    storage: Vec<Option<V>>,
+    free: Vec<usize>,
+    overflow: OverflowPolicy,
+    #[serde(default)]
+    id: u32,
+}
+
+impl<V, K, G> Serialize for HandleMap<V, K, G>
+where
+    V: Serialize,
+    K: Serialize,
+    G: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MapRef {
+            generations: self.allocator.generations(),
+            keys: &self.keys,
+            aliases: &self.aliases,
+            storage: &self.storage,
+            free: self.allocator.free(),
+            overflow: self.allocator.overflow(),
+            id: self.id.get().to_raw(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// With the `map-id` feature, the map gets its saved id, or a new one
+/// if a live map already has it, see `HandleMap::read_snapshot`.
+/// Handles saved with the map then belong to the live map.
+impl<'de, V, K, G> Deserialize<'de> for HandleMap<V, K, G>
+where
+    V: Deserialize<'de>,
+    K: Deserialize<'de> + Hash + Eq + Clone,
+    G: Deserialize<'de> + Generation,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = MapData::<V, K, G>::deserialize(deserializer)?;
+        let id = OwnedMapId::claim(data.id);
+
+        HandleMap::from_slots(
+            data.generations,
+            data.keys,
+            data.aliases,
+            data.storage,
+            data.free,
+            data.overflow,
+            id,
+        )
+        .map_err(D::Error::custom)
+    }
+}
+
+/// Written as `(index, generation, map id)`.
+impl<T, G> Serialize for Handle<T, G>
+where
+    G: Generation + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.index, self.generation, self.map.to_raw()).serialize(serializer)
+    }
+}
+
+/// Reads `(index, generation, map id)` or `(index, generation)`,
+/// in which case the map id is zero.
+impl<'de, T, G> Deserialize<'de> for Handle<T, G>
+where
+    G: Generation + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(3, HandleVisitor(PhantomData))
+    }
+}
+
+struct HandleVisitor<T, G>(PhantomData<Handle<T, G>>);
+
+impl<'de, T, G> Visitor<'de> for HandleVisitor<T, G>
+where
+    G: Generation + Deserialize<'de>,
+{
+    type Value = Handle<T, G>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a handle as (index, generation, map id) or (index, generation)")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Handle<T, G>, A::Error> {
+        let index = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        let generation = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        let map = seq.next_element()?.unwrap_or(0);
+
+        Ok(Handle::new(index, generation, MapId::from_raw(map)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use {Handle, HandleMap};
+
+    #[test]
+    fn handles_survive_round_trip() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+
+        let one = map.insert("one", 1);
+        let two = map.insert("two", 2);
+        let three = map.insert("three", 3);
+        map.remove(two);
+        let stale = map.handle("three").unwrap();
+        map.replace(three, 33);
+        let three = map.handle("three").unwrap();
+        map.add_alias(three, "drei").unwrap();
+
+        let saved = serde_json::to_string(&(&map, [one, stale, three])).unwrap();
+        // Otherwise the loaded map would get a new id, see `read_snapshot`.
+        drop(map);
+        let (mut map, [one, stale, three]): (HandleMap<u32>, [Handle<u32>; 3]) =
+            serde_json::from_str(&saved).unwrap();
+
+        assert_eq!(1, map[one]);
+        assert_eq!(33, map[three]);
+        assert!(!map.contains(stale));
+        assert_eq!(2, map.len());
+        assert_eq!(Some(three), map.handle("three"));
+        assert_eq!(Some(&"one".to_string()), map.key_of(one));
+        assert_eq!(Some(three), map.handle("drei"));
+
+        let four = map.insert("four", 4);
+        assert_eq!(two.index(), four.index());
+        assert_ne!(two.erase(), four.erase());
+    }
+
+    #[cfg(feature = "map-id")]
+    #[test]
+    fn loading_twice_keeps_ids_unique() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+        let one = map.insert("one", 1);
+        let saved = serde_json::to_string(&(&map, one)).unwrap();
+
+        // `map` keeps its id and its handles.
+        let (copy, in_copy): (HandleMap<u32>, Handle<u32>) = serde_json::from_str(&saved).unwrap();
+        assert!(map.contains(in_copy));
+        assert!(!copy.contains(in_copy));
+        assert!(!copy.contains(one));
+
+        drop(map);
+        let (first, in_first): (HandleMap<u32>, Handle<u32>) = serde_json::from_str(&saved).unwrap();
+        let (second, in_second): (HandleMap<u32>, Handle<u32>) = serde_json::from_str(&saved).unwrap();
+
+        assert_eq!(1, first[in_first]);
+        assert_eq!(1, first[in_second]);
+        assert!(!second.contains(in_second));
+        assert!(!copy.contains(in_first));
+    }
+
+    #[test]
+    fn reads_data_without_map_ids() {
+        let saved = r#"[{"generations":[1,2],"keys":["one",null],"aliases":[[],[]],
+            "storage":[1,null],"free":[1],"overflow":"Retire"},[0,1]]"#;
+        let (map, one): (HandleMap<u32>, Handle<u32>) = serde_json::from_str(saved).unwrap();
+
+        assert_eq!(Some(&1), map.get(one));
+        assert_eq!(1, map.len());
+        // Written the same way with and without `map-id`.
+        let written = serde_json::to_string(&one).unwrap();
+        assert_eq!(3, written.split(',').count());
+    }
+
+    #[test]
+    fn rejects_invalid_free_list() {
+        let mut map: HandleMap<u32> = HandleMap::new();
+        map.insert("one", 1);
+
+        let saved = serde_json::to_string(&map).unwrap().replace("\"free\":[]", "\"free\":[0]");
+
+        assert!(serde_json::from_str::<HandleMap<u32>>(&saved).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! A versioned binary format for `HandleMap`s.
+//!
+//! All integers are little endian. A snapshot consists of
+//!
+//! * the magic bytes `HMAP`,
+//! * the format version (`u16`),
+//! * the size of the generation type in bytes (`u8`),
+//! * the overflow policy (`u8`),
+//! * the map id (`u32`, zero without the `map-id` feature),
+//! * the number of slots and the length of the free list (`u64` each),
+//! * every slot: its generation, a flags byte (bit 0: has a value,
+//!   bit 1: has a key, bit 2: has aliases), then the key, the
+//!   aliases (as a `Vec`) and the value if present,
+//! * the free list (`u64` per entry),
+//! * an FNV-1a checksum of everything before it (`u64`).
+//!
+//! Keys and values are written with their `Codec` implementation.
+//! Version 1 is the same format without aliases and can still be read.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+
+use fnv::FnvHasher;
+
+use handle::OwnedMapId;
+use {Generation, HandleMap, OverflowPolicy};
+
+const MAGIC: &[u8; 4] = b"HMAP";
+const VERSION: u16 = 2;
+
+const HAS_VALUE: u8 = 1;
+const HAS_KEY: u8 = 1 << 1;
+const HAS_ALIASES: u8 = 1 << 2;
+
+/// A type that can be written to and read back from a snapshot.
+pub trait Codec: Sized {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, SnapshotError>;
+}
+
+/// The reason a snapshot could not be read.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Reading from the underlying reader failed.
+    Io(io::Error),
+    /// The snapshot ended unexpectedly.
+    Truncated,
+    /// The data does not start with the snapshot magic bytes.
+    BadMagic,
+    /// The snapshot was written by an unknown version of the format.
+    UnsupportedVersion(u16),
+    /// The snapshot was written for a different generation type.
+    GenerationWidth { expected: u8, found: u8 },
+    /// The checksum does not match the contents.
+    ChecksumMismatch,
+    /// The contents are inconsistent.
+    Corrupt(&'static str),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Truncated,
+            _ => SnapshotError::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref e) => write!(f, "failed to read snapshot: {}", e),
+            SnapshotError::Truncated => write!(f, "the snapshot is truncated"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::GenerationWidth { expected, found } => write!(
+                f,
+                "expected {}-byte generations, but the snapshot has {}-byte generations",
+                expected, found
+            ),
+            SnapshotError::ChecksumMismatch => write!(f, "the snapshot checksum does not match"),
+            SnapshotError::Corrupt(reason) => write!(f, "the snapshot is corrupt: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SnapshotError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<V, K, G> HandleMap<V, K, G>
+where
+    V: Codec,
+    K: Codec + Hash + Eq + Clone,
+    G: Generation + Codec,
+{
+    /// Writes a versioned, checksummed binary snapshot of this map.
+    ///
+    /// Reading it back with `read_snapshot` restores all handles.
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = Checksummed::new(writer);
+
+        writer.write_all(MAGIC)?;
+        VERSION.encode(&mut writer)?;
+        (size_of::<G>() as u8).encode(&mut writer)?;
+        encode_policy(self.allocator.overflow()).encode(&mut writer)?;
+        self.id.get().to_raw().encode(&mut writer)?;
+        (self.storage.len() as u64).encode(&mut writer)?;
+        (self.allocator.free().len() as u64).encode(&mut writer)?;
+
+        for index in 0..self.storage.len() {
+            let value = self.storage[index].as_ref();
+            let key = self.keys[index].as_ref();
+            let aliases = &self.aliases[index];
+
+            self.allocator.generation(index).encode(&mut writer)?;
+
+            let mut flags = 0;
+            if value.is_some() {
+                flags |= HAS_VALUE;
+            }
+            if key.is_some() {
+                flags |= HAS_KEY;
+            }
+            if !aliases.is_empty() {
+                flags |= HAS_ALIASES;
+            }
+            flags.encode(&mut writer)?;
+
+            if let Some(key) = key {
+                key.encode(&mut writer)?;
+            }
+            if !aliases.is_empty() {
+                aliases.encode(&mut writer)?;
+            }
+            if let Some(value) = value {
+                value.encode(&mut writer)?;
+            }
+        }
+
+        for &index in self.allocator.free() {
+            (index as u64).encode(&mut writer)?;
+        }
+
+        let checksum = writer.hasher.finish();
+        checksum.encode(&mut writer.inner)?;
+
+        writer.inner.flush()
+    }
+
+    /// Reads a snapshot written by `write_snapshot`.
+    ///
+    /// With the `map-id` feature, the map gets its saved id, unless
+    /// a live map already has it, e.g. if the snapshot is read twice
+    /// or its map is still around. Then the map gets a new id, so
+    /// handles to the other map are not accepted by it, and the
+    /// handles saved with the snapshot only work with the other map.
+    pub fn read_snapshot<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        let mut reader = Checksummed::new(reader);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = u16::decode(&mut reader)?;
+        if version == 0 || version > VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let width = u8::decode(&mut reader)?;
+        if width as usize != size_of::<G>() {
+            return Err(SnapshotError::GenerationWidth {
+                expected: size_of::<G>() as u8,
+                found: width,
+            });
+        }
+
+        let overflow = decode_policy(u8::decode(&mut reader)?)?;
+        let id = u32::decode(&mut reader)?;
+        let slots = decode_len(&mut reader)?;
+        let free_len = decode_len(&mut reader)?;
+
+        let mut generations = Vec::new();
+        let mut keys = Vec::new();
+        let mut aliases = Vec::new();
+        let mut storage = Vec::new();
+        for _ in 0..slots {
+            generations.push(G::decode(&mut reader)?);
+
+            let known = match version {
+                1 => HAS_VALUE | HAS_KEY,
+                _ => HAS_VALUE | HAS_KEY | HAS_ALIASES,
+            };
+            let flags = u8::decode(&mut reader)?;
+            if flags & !known != 0 {
+                return Err(SnapshotError::Corrupt("unknown slot flags"));
+            }
+
+            keys.push(if flags & HAS_KEY != 0 { Some(K::decode(&mut reader)?) } else { None });
+            aliases.push(if flags & HAS_ALIASES != 0 { Vec::decode(&mut reader)? } else { Vec::new() });
+            storage.push(if flags & HAS_VALUE != 0 { Some(V::decode(&mut reader)?) } else { None });
+        }
+
+        let mut free = Vec::new();
+        for _ in 0..free_len {
+            free.push(decode_len(&mut reader)?);
+        }
+
+        let expected = reader.hasher.finish();
+        if u64::decode(&mut reader.inner)? != expected {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        HandleMap::from_slots(generations, keys, aliases, storage, free, overflow, OwnedMapId::claim(id))
+            .map_err(SnapshotError::Corrupt)
+    }
+}
+
+/// Hashes everything that passes through it.
+struct Checksummed<T> {
+    inner: T,
+    hasher: FnvHasher,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            hasher: FnvHasher::default(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+fn encode_policy(policy: OverflowPolicy) -> u8 {
+    match policy {
+        OverflowPolicy::Retire => 0,
+        OverflowPolicy::Wrap => 1,
+        OverflowPolicy::Panic => 2,
+    }
+}
+
+fn decode_policy(byte: u8) -> Result<OverflowPolicy, SnapshotError> {
+    match byte {
+        0 => Ok(OverflowPolicy::Retire),
+        1 => Ok(OverflowPolicy::Wrap),
+        2 => Ok(OverflowPolicy::Panic),
+        _ => Err(SnapshotError::Corrupt("unknown overflow policy")),
+    }
+}
+
+/// Reads a `u64` length or index, failing if it does not fit a `usize`.
+fn decode_len<R: Read>(reader: &mut R) -> Result<usize, SnapshotError> {
+    let len = u64::decode(reader)?;
+
+    if len > usize::MAX as u64 {
+        return Err(SnapshotError::Corrupt("length too big"));
+    }
+
+    Ok(len as usize)
+}
+
+macro_rules! impl_codec_num {
+    ($($ty:ty),*) => {
+        $(
+            impl Codec for $ty {
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+                    let mut bytes = [0; size_of::<$ty>()];
+                    reader.read_exact(&mut bytes)?;
+
+                    Ok(<$ty>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_codec_num!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Codec for bool {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt("invalid bool")),
+        }
+    }
+}
+
+impl Codec for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(writer)?;
+
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+        let len = u64::decode(reader)?;
+
+        let mut bytes = Vec::new();
+        reader.take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        String::from_utf8(bytes).map_err(|_| SnapshotError::Corrupt("invalid UTF-8"))
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(writer)?;
+
+        self.iter().try_for_each(|e| e.encode(writer))
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+        let len = decode_len(reader)?;
+
+        // Don't trust `len` to preallocate.
+        let mut vec = Vec::new();
+        for _ in 0..len {
+            vec.push(T::decode(reader)?);
+        }
+
+        Ok(vec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VERSION;
+    use {HandleMap, SnapshotError};
+
+    fn sample() -> HandleMap<String> {
+        let mut map = HandleMap::new();
+
+        map.insert("one", "1".to_string());
+        let two = map.insert("two", "2".to_string());
+        let three = map.insert("three", "3".to_string());
+        map.add_alias(three, "drei").unwrap();
+        map.remove(two);
+
+        map
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = sample();
+        let one = map.handle("one").unwrap();
+        let three = map.handle("three").unwrap();
+
+        let mut bytes = Vec::new();
+        map.write_snapshot(&mut bytes).unwrap();
+        // Otherwise the loaded map would get a new id, see `read_snapshot`.
+        drop(map);
+        let mut map: HandleMap<String> = HandleMap::read_snapshot(&bytes[..]).unwrap();
+
+        assert_eq!("1", map[one]);
+        assert_eq!("3", map[three]);
+        assert_eq!(2, map.len());
+        assert_eq!(Some(one), map.handle("one"));
+        assert_eq!(Some(three), map.handle("drei"));
+
+        let four = map.insert("four", "4".to_string());
+        assert_eq!(1, four.index());
+    }
+
+    #[test]
+    fn errors() {
+        let mut map = sample();
+        map.insert("four", "payload".to_string());
+        let mut bytes = Vec::new();
+        map.write_snapshot(&mut bytes).unwrap();
+
+        let read = |bytes: &[u8]| HandleMap::<String>::read_snapshot(bytes).err().unwrap();
+
+        match read(&bytes[..bytes.len() - 3]) {
+            SnapshotError::Truncated => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Still valid UTF-8, so only the checksum can notice.
+        let payload = bytes.windows(7).position(|window| window == b"payload").unwrap();
+        let mut corrupt = bytes.clone();
+        corrupt[payload] ^= 0x01;
+        match read(&corrupt) {
+            SnapshotError::ChecksumMismatch => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        match read(&newer) {
+            SnapshotError::UnsupportedVersion(v) if v == VERSION + 1 => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match read(b"HMAQ") {
+            SnapshotError::BadMagic => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match HandleMap::<String, String, u32>::read_snapshot(&bytes[..]).err().unwrap() {
+            SnapshotError::GenerationWidth { expected: 4, found: 2 } => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::{Index, IndexMut};
+use std::pin::Pin;
+
+use fnv::FnvHashMap;
+
+use allocator::SlotAllocator;
+use handle::OwnedMapId;
+use {Generation, Handle, HandleError, OverflowPolicy};
+
+/// The number of slots per page of `StableHandleMap::new`.
+const PAGE_LEN: usize = 64;
+
+/// A map like `HandleMap` whose elements never move.
+///
+/// Elements are stored in fixed-size pages that are allocated
+/// once and never reallocated, so the address of an element stays
+/// the same until it is removed, no matter how much is inserted.
+/// That also makes it possible to pin elements, see `get_pin_mut`.
+///
+/// Moving elements out, with `remove` or `get_mut`, is only possible
+/// if they are `Unpin`. Other elements are dropped in place by `delete`.
+pub struct StableHandleMap<V, K = String, G = u16> {
+    allocator: SlotAllocator<G>,
+    keys_to_indices: FnvHashMap<K, Handle<V, G>>,
+    /// The key of every slot.
+    keys: Vec<Option<K>>,
+    pages: Vec<Box<[Option<V>]>>,
+    page_len: usize,
+    len: usize,
+    id: OwnedMapId,
+}
+
+impl<V, K, G> StableHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    #[inline]
+    pub fn new() -> Self {
+        StableHandleMap::with_page_len(PAGE_LEN)
+    }
+
+    /// Creates a map that allocates `page_len` slots at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_len` is zero.
+    pub fn with_page_len(page_len: usize) -> Self {
+        assert!(page_len > 0, "Pages must not be empty");
+
+        StableHandleMap {
+            allocator: SlotAllocator::new(),
+            keys_to_indices: Default::default(),
+            keys: Vec::new(),
+            pages: Vec::new(),
+            page_len,
+            len: 0,
+            id: OwnedMapId::next(),
+        }
+    }
+
+    /// Returns the handle stored under `key`.
+    pub fn handle<Q>(&self, key: &Q) -> Option<Handle<V, G>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.keys_to_indices.get(key).copied()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.allocator.overflow()
+    }
+
+    /// See `HandleMap::set_overflow_policy`.
+    #[inline]
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.allocator.set_overflow(policy);
+    }
+
+    /// Returns `true` if `handle` points to a live element of this map.
+    #[inline]
+    pub fn contains(&self, handle: Handle<V, G>) -> bool {
+        self.check(handle).is_ok()
+    }
+
+    /// Returns a reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get(&self, handle: Handle<V, G>) -> Option<&V> {
+        self.check(handle).ok()?;
+
+        self.slot(handle.index).as_ref()
+    }
+
+    /// Like `get`, but returns the element pinned,
+    /// as it never moves until it is removed.
+    pub fn get_pin(&self, handle: Handle<V, G>) -> Option<Pin<&V>> {
+        // Safety: elements are only moved out of their slot
+        // by `remove`, which requires `V: Unpin`.
+        self.get(handle).map(|value| unsafe { Pin::new_unchecked(value) })
+    }
+
+    /// Returns a pinned mutable reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get_pin_mut(&mut self, handle: Handle<V, G>) -> Option<Pin<&mut V>> {
+        self.check(handle).ok()?;
+
+        // Safety: see `get_pin`.
+        self.slot_mut(handle.index).as_mut().map(|value| unsafe { Pin::new_unchecked(value) })
+    }
+
+    /// Returns the key of the element `handle` points to,
+    /// or `None` if it is dead or anonymous.
+    pub fn key_of(&self, handle: Handle<V, G>) -> Option<&K> {
+        self.check(handle).ok()?;
+
+        self.keys[handle.index].as_ref()
+    }
+
+    /// Inserts a value under `key`, see `HandleMap::insert`.
+    ///
+    /// Never moves any of the other elements.
+    pub fn insert<S>(&mut self, key: S, value: V) -> Handle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let key = key.into();
+        let handle = self.push(value);
+
+        if let Some(old) = self.handle(&key) {
+            self.delete(old);
+        }
+        self.keys[handle.index] = Some(key.clone());
+        self.keys_to_indices.insert(key, handle);
+
+        handle
+    }
+
+    /// Inserts a value without a key.
+    pub fn insert_anonymous(&mut self, value: V) -> Handle<V, G> {
+        self.push(value)
+    }
+
+    /// Drops the element `handle` points to in place,
+    /// freeing its slot and invalidating all handles to it.
+    ///
+    /// Returns `false` if the element was already removed.
+    pub fn delete(&mut self, handle: Handle<V, G>) -> bool {
+        if self.check(handle).is_err() {
+            return false;
+        }
+
+        *self.slot_mut(handle.index) = None;
+        self.vacate(handle.index);
+
+        true
+    }
+
+    /// Removes the element stored under `key` and drops it in place.
+    pub fn delete_key<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match self.handle(key) {
+            Some(handle) => self.delete(handle),
+            None => false,
+        }
+    }
+
+    /// Stores `value` in a free slot without assigning it a key,
+    /// allocating a new page if there is none.
+    fn push(&mut self, value: V) -> Handle<V, G> {
+        let index = self.allocator.alloc();
+        if index == self.keys.len() {
+            if index == self.pages.len() * self.page_len {
+                self.pages.push((0..self.page_len).map(|_| None).collect());
+            }
+
+            self.keys.push(None);
+        }
+
+        *self.slot_mut(index) = Some(value);
+        self.len += 1;
+
+        Handle::new(index, self.allocator.generation(index), self.id.get())
+    }
+
+    fn slot(&self, index: usize) -> &Option<V> {
+        &self.pages[index / self.page_len][index % self.page_len]
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Option<V> {
+        &mut self.pages[index / self.page_len][index % self.page_len]
+    }
+
+    /// Puts the already emptied slot at `index` on the free list,
+    /// unless it is retired, and removes its key.
+    fn vacate(&mut self, index: usize) {
+        if let Some(key) = self.keys[index].take() {
+            self.keys_to_indices.remove(&key);
+        }
+        self.len -= 1;
+        self.allocator.release(index);
+    }
+
+    /// Checks whether `handle` points to a live element of this map.
+    fn check(&self, handle: Handle<V, G>) -> Result<(), HandleError> {
+        if handle.map != self.id.get() {
+            return Err(HandleError::WrongMap);
+        }
+
+        self.allocator.check(handle.index, handle.generation, |index| self.slot(index).is_some())
+    }
+}
+
+impl<V, K, G> StableHandleMap<V, K, G>
+where
+    V: Unpin,
+    K: Hash + Eq,
+    G: Generation,
+{
+    /// Returns a mutable reference to the element `handle` points to,
+    /// or `None` if the handle is dead or from another map.
+    pub fn get_mut(&mut self, handle: Handle<V, G>) -> Option<&mut V> {
+        self.get_pin_mut(handle).map(Pin::into_inner)
+    }
+
+    /// Removes the element `handle` points to, moving it out of the map.
+    ///
+    /// Returns `None` if the element was already removed.
+    pub fn remove(&mut self, handle: Handle<V, G>) -> Option<V> {
+        self.check(handle).ok()?;
+
+        let value = self.slot_mut(handle.index).take();
+        self.vacate(handle.index);
+
+        value
+    }
+
+    /// Removes the element stored under `key`.
+    pub fn remove_key<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let handle = self.handle(key)?;
+
+        self.remove(handle)
+    }
+}
+
+impl<V, K, G> Default for StableHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn default() -> Self {
+        StableHandleMap::new()
+    }
+}
+
+impl<V, K, G> Index<Handle<V, G>> for StableHandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    type Output = V;
+
+    fn index(&self, handle: Handle<V, G>) -> &V {
+        if let Err(e) = self.check(handle) {
+            panic!("Tried to use invalid handle: {}", e);
+        }
+
+        self.slot(handle.index).as_ref().unwrap()
+    }
+}
+
+impl<V, K, G> IndexMut<Handle<V, G>> for StableHandleMap<V, K, G>
+where
+    V: Unpin,
+    K: Hash + Eq,
+    G: Generation,
+{
+    fn index_mut(&mut self, handle: Handle<V, G>) -> &mut V {
+        if let Err(e) = self.check(handle) {
+            panic!("Tried to use invalid handle: {}", e);
+        }
+
+        self.slot_mut(handle.index).as_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomPinned;
+    use std::pin::Pin;
+
+    use StableHandleMap;
+
+    #[test]
+    fn addresses_are_stable() {
+        let mut map: StableHandleMap<_> = StableHandleMap::with_page_len(4);
+
+        let first = map.insert("first", [0u8; 16]);
+        let address = map[first].as_ptr();
+
+        for i in 0..100 {
+            map.insert_anonymous([i; 16]);
+        }
+        map[first][0] = 1;
+
+        assert_eq!(address, map[first].as_ptr());
+        assert_eq!(101, map.len());
+        assert_eq!(Some(1), map.remove_key("first").map(|v| v[0]));
+        assert!(!map.contains(first));
+    }
+
+    struct Buffer {
+        data: u32,
+        _pinned: PhantomPinned,
+    }
+
+    #[test]
+    fn pinned_elements() {
+        let mut map: StableHandleMap<_> = StableHandleMap::new();
+
+        let handle = map.insert(
+            "buffer",
+            Buffer {
+                data: 1,
+                _pinned: PhantomPinned,
+            },
+        );
+
+        let pinned: Pin<&mut Buffer> = map.get_pin_mut(handle).unwrap();
+        // Safety: `data` is not structurally pinned.
+        unsafe { pinned.get_unchecked_mut().data = 2 };
+
+        assert_eq!(2, map.get_pin(handle).unwrap().data);
+        assert!(map.delete_key("buffer"));
+        assert!(!map.delete(handle));
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+//! Reference counted handles.
+//!
+//! A `StrongHandle` keeps its element alive until `collect_garbage`
+//! is called after the last strong handle to it was dropped. Plain
+//! `Handle`s don't count, they act as weak handles.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use {Generation, Handle, HandleMap};
+
+/// A handle that counts as a use of its element.
+///
+/// Cloning it increments the reference count of the element,
+/// dropping it decrements it. Elements without any strong handles
+/// left are removed by `HandleMap::collect_garbage`.
+pub struct StrongHandle<T = (), G = u16> {
+    handle: Handle<T, G>,
+    count: Arc<()>,
+}
+
+impl<T, G> StrongHandle<T, G>
+where
+    G: Generation,
+{
+    /// Returns the weak handle, which does not keep the element alive.
+    pub fn handle(&self) -> Handle<T, G> {
+        self.handle
+    }
+}
+
+impl<T, G: Generation> Clone for StrongHandle<T, G> {
+    fn clone(&self) -> Self {
+        StrongHandle {
+            handle: self.handle,
+            count: self.count.clone(),
+        }
+    }
+}
+
+impl<T, G: Generation> fmt::Debug for StrongHandle<T, G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("StrongHandle").field(&self.handle).finish()
+    }
+}
+
+impl<T, G: Generation> PartialEq for StrongHandle<T, G> {
+    fn eq(&self, other: &Self) -> bool {
+        self.handle == other.handle
+    }
+}
+
+impl<T, G: Generation> Eq for StrongHandle<T, G> {}
+
+impl<T, G: Generation> Hash for StrongHandle<T, G> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.handle.hash(state);
+    }
+}
+
+impl<V, K, G> HandleMap<V, K, G>
+where
+    K: Hash + Eq,
+    G: Generation,
+{
+    /// Returns a strong handle to the element `handle` points to,
+    /// or `None` if the handle is dead.
+    ///
+    /// From then on the element is removed by `collect_garbage`
+    /// once all strong handles to it are dropped. Removing or
+    /// replacing it explicitly makes its strong handles stale.
+    pub fn strong(&mut self, handle: Handle<V, G>) -> Option<StrongHandle<V, G>> {
+        self.check(handle).ok()?;
+
+        let count = self.strong.entry(handle.index).or_default().clone();
+
+        Some(StrongHandle { handle, count })
+    }
+
+    /// Inserts a value under `key` and returns a strong handle to it.
+    ///
+    /// See `insert` and `strong`.
+    pub fn insert_strong<S>(&mut self, key: S, value: V) -> StrongHandle<V, G>
+    where
+        S: Into<K>,
+        K: Clone,
+    {
+        let handle = self.insert(key, value);
+
+        self.strong(handle).expect("Bug: inserted element is dead")
+    }
+
+    /// Returns the number of strong handles to the element `handle`
+    /// points to. Zero if it has none or the handle is dead.
+    pub fn strong_count(&self, handle: Handle<V, G>) -> usize {
+        if self.check(handle).is_err() {
+            return 0;
+        }
+
+        // The map holds one reference itself.
+        self.strong.get(&handle.index).map_or(0, |count| Arc::strong_count(count) - 1)
+    }
+
+    /// Removes all elements whose strong handles were all dropped,
+    /// returning them together with their keys.
+    ///
+    /// Elements that never had a strong handle are kept.
+    pub fn collect_garbage(&mut self) -> Vec<(Option<K>, V)> {
+        let unused: Vec<usize> = self
+            .strong
+            .iter()
+            .filter(|&(_, count)| Arc::strong_count(count) == 1)
+            .map(|(&index, _)| index)
+            .collect();
+
+        unused
+            .into_iter()
+            .map(|index| {
+                let key = self.keys[index].take();
+                if let Some(ref key) = key {
+                    self.keys_to_indices.remove(key);
+                }
+
+                let handle = self.handle_at(index);
+                let value = self.remove(handle).expect("Bug: counted slot is empty");
+
+                (key, value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use HandleMap;
+
+    #[test]
+    fn collect_unused() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let texture = map.insert_strong("texture", 1);
+        let copy = texture.clone();
+        let weak = map.insert("weak", 2);
+        let anonymous = map.insert_anonymous(3);
+        let anonymous = map.strong(anonymous).unwrap();
+
+        assert_eq!(2, map.strong_count(texture.handle()));
+        assert_eq!(0, map.strong_count(weak));
+        assert!(map.collect_garbage().is_empty());
+
+        drop(texture);
+        assert_eq!(1, map.strong_count(copy.handle()));
+        assert!(map.collect_garbage().is_empty());
+
+        let handle = copy.handle();
+        drop(copy);
+        drop(anonymous);
+        let mut collected = map.collect_garbage();
+        collected.sort();
+
+        assert_eq!(vec![(None, 3), (Some("texture".to_string()), 1)], collected);
+        assert!(!map.contains(handle));
+        assert_eq!(None, map.handle("texture"));
+        assert_eq!(Some(weak), map.handle("weak"));
+    }
+
+    #[test]
+    fn replace_makes_strong_handles_stale() {
+        let mut map: HandleMap<_> = HandleMap::new();
+
+        let strong = map.insert_strong("one", 1);
+        map.replace(strong.handle(), 11);
+        let new = map.handle("one").unwrap();
+
+        assert_eq!(0, map.strong_count(new));
+        assert!(map.collect_garbage().is_empty());
+        assert_eq!(11, map[new]);
+
+        map.remove(new);
+        let reused = map.insert_strong("two", 2);
+        drop(strong);
+        assert_eq!(1, map.strong_count(reused.handle()));
+    }
+}